@@ -1,16 +1,27 @@
-use std::fs::File;
+use std::{
+    fs::File,
+    hash::{DefaultHasher, Hash, Hasher},
+    ops::Deref,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
 
-use anyhow::{bail, Error};
+use anyhow::{bail, Context as _, Error};
 use half::f16;
 use memmap2::Mmap;
 use safetensors::SafeTensors;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
 use tracing::{event, Level};
 use web_rwkv::runtime::model::ModelVersion;
 use web_rwkv::{
     context::{Context, ContextBuilder, InstanceExt},
-    model::{loader::Loader, ContextAutoLimits},
+    model::{
+        loader::Loader, ContextAutoLimits, ModelInfo as LoaderInfo, ModelVersion as LoaderVersion,
+    },
     runtime::{
-        infer::{InferInput, InferInputBatch, InferOption, InferOutput},
+        infer::{InferInput, InferOutput},
         model::{Build, ModelBuilder, ModelRuntime, Quant, State},
         softmax::softmax_one,
         v4, v5, v6, JobRuntime,
@@ -20,30 +31,53 @@ use web_rwkv::{
 };
 use wgpu::{Instance, PowerPreference};
 
-use crate::sampler::SamplerSettings;
+use crate::sampler::{choice_seed, SamplerSettings};
 use crate::{
-    config::ModelConfig,
+    batch::{Batcher, RwkvRuntime},
+    config::{ModelConfig, Quantization},
+    error::AgentError,
     sampler::Sampler,
-    types::{ChatMessage, ModelInfo},
+    types::{ChatMessage, ModelInfo, ModelMetadata, TokenLogprob, TopLogprob},
 };
 
 pub struct ActiveModel {
     id: String,
     config: ModelConfig,
+    quantization: Quantization,
+    model_info: LoaderInfo,
+    weights: WeightsMetadata,
+    load_duration: Duration,
 
     tokenizer: Tokenizer,
     context: Context,
-    runtime: JobRuntime<InferInput, InferOutput>,
-    state: Box<dyn State + Send + Sync>,
+    state: Arc<dyn State + Send + Sync>,
     initial_state: TensorCpu<f32>,
+
+    batcher: Batcher,
+    slots: Arc<Semaphore>,
+    free_slots: Mutex<Vec<usize>>,
+}
+
+/// One of the model's batch slots, with its own state, reserved for a single request.
+///
+/// Requests holding different slots are run together. The slot is freed when dropped.
+pub struct ModelSlot {
+    model: Arc<ActiveModel>,
+    index: usize,
+    _permit: OwnedSemaphorePermit,
 }
 
 impl ActiveModel {
     pub(crate) async fn create(
         id: String,
         config: ModelConfig,
-        quant_nf8: bool,
+        weights_path: &Path,
+        quantization: Quantization,
+        num_slots: usize,
     ) -> Result<Self, Error> {
+        let started = Instant::now();
+        let weights = read_weights_metadata(weights_path)?;
+
         // Load the tokenizer
         let contents = std::fs::read_to_string(&config.vocab)?;
         let tokenizer = Tokenizer::new(&contents)?;
@@ -55,60 +89,125 @@ impl ActiveModel {
             "rwkv6" => ModelVersion::V6,
             _ => bail!("unsupported architecture"),
         };
-        let weights_path = format!("data/{}.st", id);
-        let (context, runtime, state) = load_model(version, &weights_path, quant_nf8).await?;
+        let (context, runtime, state, model_info) =
+            load_model(version, weights_path, quantization, num_slots).await?;
+
+        if let Some(layer) = config.embedding_layer {
+            if layer >= model_info.num_layer {
+                bail!(
+                    "embedding_layer {} is out of range, the model has {} layers",
+                    layer,
+                    model_info.num_layer
+                );
+            }
+        }
 
         // Get the initial state if we need to reset
         let initial_state = state.back(0).await?;
 
+        let batcher = Batcher::start(RwkvRuntime {
+            runtime,
+            state: state.clone(),
+        });
+
         let value = Self {
             id,
             config,
+            quantization,
+            model_info,
+            weights,
+            load_duration: started.elapsed(),
 
             tokenizer,
             context,
-            runtime,
             state,
             initial_state,
+
+            batcher,
+            slots: Arc::new(Semaphore::new(num_slots)),
+            free_slots: Mutex::new((0..num_slots).rev().collect()),
         };
 
         Ok(value)
     }
 
+    /// Wait for a free slot, slots are handed out in the order they were requested.
+    pub async fn acquire_slot(self: &Arc<Self>) -> Result<ModelSlot, Error> {
+        let permit = self.slots.clone().acquire_owned().await?;
+
+        let index = self
+            .free_slots
+            .lock()
+            .unwrap()
+            .pop()
+            .context("no free slot despite permit")?;
+
+        self.batcher.acquire(index);
+
+        let value = ModelSlot {
+            model: self.clone(),
+            index,
+            _permit: permit,
+        };
+        Ok(value)
+    }
+
+    pub fn quantization(&self) -> Quantization {
+        self.quantization
+    }
+
+    /// How long it took to load the model.
+    pub fn load_duration(&self) -> Duration {
+        self.load_duration
+    }
+
+    /// Amount of requests that can be run at the same time.
+    pub fn num_slots(&self) -> usize {
+        self.state.num_batch()
+    }
+
     /// Get metadata information of the currently loaded model.
     pub fn info(&self) -> ModelInfo {
+        let architecture = match self.model_info.version {
+            LoaderVersion::V4 => "rwkv4",
+            LoaderVersion::V5 => "rwkv5",
+            LoaderVersion::V6 => "rwkv6",
+        };
+        let metadata = ModelMetadata {
+            architecture: architecture.to_string(),
+            num_layers: self.model_info.num_layer,
+            embedding_size: self.model_info.num_emb,
+            vocab_size: self.model_info.num_vocab,
+            quantization: self.quantization,
+            file_size: self.weights.size,
+        };
+
         ModelInfo {
             id: self.id.clone(),
             object: "model".to_string(),
-            created: 1715960329,
-            owned_by: "Recursal AI".to_string(),
+            created: self.weights.created,
+            owned_by: self.config.owned_by.clone(),
+            description: self.config.description.clone(),
+            loaded: true,
+            metadata: Some(metadata),
         }
     }
 
-    /// Reset model state to a clear initial state.
-    pub fn reset_state(&self) -> Result<(), Error> {
-        self.state.load(self.initial_state.clone(), 0)?;
-        Ok(())
-    }
+    /// Get a fingerprint of the configuration generating output.
+    ///
+    /// Along with the seed and the prompt, this determines which output gets generated.
+    pub fn fingerprint(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        env!("CARGO_PKG_VERSION").hash(&mut hasher);
+        self.id.hash(&mut hasher);
+        self.quantization.hash(&mut hasher);
+        self.config.banned_tokens.hash(&mut hasher);
 
-    pub async fn export_state(&self) -> Result<TensorCpu<f32>, Error> {
-        let state = self.state.back(0).await?;
-        Ok(state)
+        format!("fp_{:016x}", hasher.finish())
     }
 
-    pub fn import_state(&self, state: TensorCpu<f32>) -> Result<(), Error> {
-        self.state.load(state, 0)?;
-        Ok(())
-    }
-
-    pub async fn process_message(&self, message: &ChatMessage) -> Result<(), Error> {
-        event!(
-            Level::DEBUG,
-            role = message.role,
-            len = message.content.len(),
-            "processing message"
-        );
-
+    /// Encode a message into tokens, assembled with the role's prompt format.
+    pub fn assemble_message(&self, message: &ChatMessage) -> Result<Vec<u16>, Error> {
         // Encode content into tokens
         let content = self.tokenizer.encode(message.content.as_bytes())?;
 
@@ -117,128 +216,687 @@ impl ActiveModel {
             "system" => &self.config.role_system,
             "user" => &self.config.role_user,
             "assistant" => &self.config.role_assistant,
-            _ => bail!("invalid role"),
+            role => {
+                let message = format!("invalid role {:?}", role);
+                return Err(AgentError::invalid_input(message, "messages").into());
+            }
         };
 
         let mut assembled = role.prefix.clone();
         assembled.extend_from_slice(&content);
         assembled.extend_from_slice(&role.suffix);
 
-        // Process the tokens into the active state
-        self.process_tokens(assembled).await?;
+        Ok(assembled)
+    }
+
+    /// Encode messages into the full prompt processed before generating a reply, including the
+    /// prompt format of the assistant message that follows.
+    pub fn assemble_prompt(&self, messages: &[ChatMessage]) -> Result<Vec<u16>, Error> {
+        let mut assembled = Vec::new();
+        for message in messages {
+            assembled.extend(self.assemble_message(message)?);
+        }
+        assembled.extend_from_slice(&self.config.role_assistant.prefix);
+
+        Ok(assembled)
+    }
+
+    /// Encode text into tokens using the model's vocab.
+    pub fn tokenize(&self, text: &str) -> Result<Vec<u16>, Error> {
+        let tokens = self.tokenizer.encode(text.as_bytes())?;
+        Ok(tokens)
+    }
+
+    /// Decode tokens back into bytes using the model's vocab.
+    ///
+    /// A single token doesn't always decode to valid UTF-8 on its own.
+    pub fn detokenize(&self, tokens: &[u16]) -> Result<Vec<u8>, Error> {
+        self.validate_tokens(tokens, "tokens")?;
+        let bytes = self.tokenizer.decode(tokens)?;
+        Ok(bytes)
+    }
+
+    /// Check that client provided tokens exist in the model's vocab.
+    fn validate_tokens(&self, tokens: &[u16], param: &str) -> Result<(), AgentError> {
+        let num_vocab = self.model_info.num_vocab;
+
+        if let Some(token) = tokens.iter().find(|&&token| token as usize >= num_vocab) {
+            let message = format!("token {} is not in the vocab", token);
+            return Err(AgentError::invalid_input(message, param));
+        }
 
         Ok(())
     }
 
+    /// Get the log probability of a sampled token, and of the `top` most likely alternatives.
+    fn token_logprob(
+        &self,
+        token: u16,
+        probabilities: &[f32],
+        top: usize,
+    ) -> Result<TokenLogprob, Error> {
+        let top_logprobs = most_likely_tokens(probabilities, top)
+            .into_iter()
+            .map(|token| {
+                let (token, bytes, logprob) = self.token_info(token, probabilities)?;
+                Ok(TopLogprob {
+                    token,
+                    logprob,
+                    bytes,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        let (token, bytes, logprob) = self.token_info(token, probabilities)?;
+        let value = TokenLogprob {
+            token,
+            logprob,
+            bytes,
+            top_logprobs,
+        };
+
+        Ok(value)
+    }
+
+    fn token_info(
+        &self,
+        token: u16,
+        probabilities: &[f32],
+    ) -> Result<(String, Vec<u8>, f32), Error> {
+        let bytes = self.detokenize(&[token])?;
+        let text = String::from_utf8_lossy(&bytes).to_string();
+        let logprob = probabilities[token as usize].ln();
+
+        Ok((text, bytes, logprob))
+    }
+
+    /// Decode the final content, trimming the matched stop string and anything after it.
+    ///
+    /// Tokens are trimmed along with it, leaving only those the returned content consists of.
+    fn finalize_generated(
+        &self,
+        format: &OutputFormat,
+        stop: &[String],
+        tokens: &mut Vec<u16>,
+    ) -> Result<(String, bool), Error> {
+        // Trim stop tokens, if we got them at the end
+        if !format.stop_sequence.is_empty() && tokens.ends_with(format.stop_sequence) {
+            tokens.truncate(tokens.len() - format.stop_sequence.len());
+        }
+
+        // Decode the tokenized answer
+        let answer_bytes = self.tokenizer.decode(tokens)?;
+        let mut content = format.bytes_to_content(&answer_bytes);
+
+        let Some(position) = find_stop_string(stop, &content) else {
+            return Ok((content, false));
+        };
+
+        // Keep only the tokens the remaining content starts in, accounting for a trimmed space
+        let trimmed = answer_bytes.len().saturating_sub(content.len());
+        let kept_bytes = position + trimmed;
+        content.truncate(position);
+
+        let mut start = 0;
+        let mut kept_tokens = 0;
+        for token in tokens.iter() {
+            if start >= kept_bytes {
+                break;
+            }
+            start += self.tokenizer.decode(&[*token])?.len();
+            kept_tokens += 1;
+        }
+        tokens.truncate(kept_tokens);
+
+        Ok((content, true))
+    }
+}
+
+impl ModelSlot {
+    /// Reset the slot's state to a clear initial state.
+    pub async fn reset_state(&self) -> Result<(), Error> {
+        self.batcher
+            .load(self.index, self.initial_state.clone())
+            .await
+    }
+
+    pub async fn export_state(&self) -> Result<TensorCpu<f32>, Error> {
+        self.batcher.back(self.index).await
+    }
+
+    pub async fn import_state(&self, state: TensorCpu<f32>) -> Result<(), Error> {
+        self.batcher.load(self.index, state).await
+    }
+
+    /// Process a message into the slot's state, returning the amount of tokens processed.
+    pub async fn process_message(
+        &self,
+        message: &ChatMessage,
+        cancel: &CancellationToken,
+    ) -> Result<usize, Error> {
+        event!(
+            Level::DEBUG,
+            role = message.role,
+            len = message.content.len(),
+            "processing message"
+        );
+
+        let assembled = self.assemble_message(message)?;
+
+        // Process the tokens into the active state
+        let processed = assembled.len();
+        self.process_tokens(assembled, cancel).await?;
+
+        Ok(processed)
+    }
+
+    /// Generate an assistant message, calling `on_content` with every new piece of content as
+    /// it gets decoded.
     pub async fn generate_message(
         &self,
-        max_tokens: usize,
-        settings: &SamplerSettings,
-    ) -> Result<String, Error> {
+        settings: &GenerateSettings,
+        on_content: impl FnMut(&str),
+    ) -> Result<Generation, Error> {
         event!(Level::DEBUG, "generating message");
 
         // Start with the prompt format of an assistant message
-        let mut tokens = self.config.role_assistant.prefix.clone();
-        let mut next_input = tokens.pop().unwrap();
-        self.process_tokens(tokens).await?;
+        let prompt = self.config.role_assistant.prefix.clone();
+        let format = OutputFormat {
+            stop_sequence: &self.config.stop_sequence,
+            trim_leading_space: true,
+        };
+
+        self.generate(prompt, &format, settings, on_content).await
+    }
+
+    /// Generate `n` alternative assistant messages from the current state.
+    ///
+    /// RWKV state is fixed-size, so instead of processing the prompt again for every choice, the
+    /// state is forked. `on_content` receives the index of the choice content belongs to.
+    pub async fn generate_messages(
+        &self,
+        n: usize,
+        settings: &GenerateSettings,
+        mut on_content: impl FnMut(usize, &str),
+    ) -> Result<Vec<Generation>, Error> {
+        let state = self.export_state().await?;
+
+        let mut generations = Vec::with_capacity(n);
+        for index in 0..n {
+            if index != 0 {
+                self.import_state(state.clone()).await?;
+            }
+
+            // Every choice needs its own seed, or they would all be identical
+            let mut settings = settings.clone();
+            settings.sampler.seed = choice_seed(settings.sampler.seed, index);
+
+            let generation = self
+                .generate_message(&settings, |content| on_content(index, content))
+                .await?;
+            generations.push(generation);
+        }
+
+        Ok(generations)
+    }
+
+    /// Generate a raw continuation of `prompt`, without applying any prompt format.
+    ///
+    /// The model's stop sequence is part of its chat format, so it's not used here.
+    pub async fn generate_completion(
+        &self,
+        prompt: Vec<u16>,
+        settings: &GenerateSettings,
+        on_content: impl FnMut(&str),
+    ) -> Result<Generation, Error> {
+        event!(Level::DEBUG, len = prompt.len(), "generating completion");
+
+        self.validate_tokens(&prompt, "prompt")?;
+
+        let format = OutputFormat {
+            stop_sequence: &[],
+            trim_leading_space: false,
+        };
+
+        self.generate(prompt, &format, settings, on_content).await
+    }
+
+    /// Get an embedding vector of `tokens`, derived from the state after processing them.
+    ///
+    /// Uses the state of the configured embedding layer, or the mean over all layers if none is
+    /// configured. The vector is normalized to unit length.
+    pub async fn embed(
+        &self,
+        tokens: Vec<u16>,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<f32>, Error> {
+        if tokens.is_empty() {
+            return Err(AgentError::invalid_input("input is empty", "input").into());
+        }
+        self.validate_tokens(&tokens, "input")?;
+
+        event!(Level::DEBUG, len = tokens.len(), "embedding input");
+
+        // Embeddings always start from a clean state
+        self.reset_state().await?;
+        self.process_tokens(tokens, cancel).await?;
+        let backed = self.export_state().await?;
+
+        let num_layer = self.model_info.num_layer;
+        let layers = match self.config.embedding_layer {
+            Some(layer) => layer..layer + 1,
+            None => 0..num_layer,
+        };
+
+        // Pool the state of all selected layers
+        let mut embedding = vec![0.0; self.model_info.num_emb];
+        for layer in layers.clone() {
+            let vector = self.state.embed(layer, backed.clone())?;
+            for (value, x) in embedding.iter_mut().zip(vector.iter()) {
+                *value += x / layers.len() as f32;
+            }
+        }
+
+        let length = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if length > 0.0 {
+            embedding.iter_mut().for_each(|x| *x /= length);
+        }
+
+        Ok(embedding)
+    }
+
+    async fn generate(
+        &self,
+        mut prompt: Vec<u16>,
+        format: &OutputFormat<'_>,
+        settings: &GenerateSettings,
+        mut on_content: impl FnMut(&str),
+    ) -> Result<Generation, Error> {
+        let prompt_tokens = prompt.len();
+        let started = Instant::now();
+
+        // The last prompt token is the first input of the generation loop
+        let mut next_input = prompt
+            .pop()
+            .ok_or_else(|| AgentError::invalid_input("prompt is empty", "prompt"))?;
+        self.process_tokens(prompt, &settings.cancel).await?;
+        let prefill_time = started.elapsed();
 
         // Generate answer tokens
-        let mut sampler = Sampler::default();
+        let mut sampler = Sampler::new(&settings.sampler);
         let mut generated = Vec::new();
+        let mut logprobs = settings.logprobs.map(|_| Vec::new());
+        let mut decoder = PartialDecoder::default();
+        let mut streamed = 0;
 
-        while !self.should_stop_generation(max_tokens, &generated) {
-            // Run model step
-            let batch = InferInputBatch {
-                tokens: vec![next_input],
-                option: InferOption::Last,
-            };
-            let input = InferInput::new(vec![batch], 32);
-            let (_input, output) = self.runtime.infer(input).await;
+        let mut stop_reason = loop {
+            if let Some(reason) = should_stop_generation(format, settings.max_tokens, &generated) {
+                break reason;
+            }
+
+            if settings.cancel.is_cancelled() {
+                event!(Level::INFO, "generation cancelled");
+                break StopReason::Cancelled;
+            }
 
-            let logits = &output[0].0;
+            // Run model step, together with whatever other slots are doing
+            let result = self
+                .batcher
+                .infer(self.index, vec![next_input], &settings.cancel)
+                .await;
+            let logits = match result {
+                Ok(logits) => logits,
+                Err(error) if matches!(error.downcast_ref(), Some(AgentError::Cancelled)) => {
+                    event!(Level::INFO, "generation cancelled");
+                    break StopReason::Cancelled;
+                }
+                Err(error) => return Err(error),
+            };
 
             // Pick output token
-            let logits = sampler.apply_penalties(settings, logits)?;
+            let logits =
+                sampler.apply_penalties(&settings.sampler, &self.config.banned_tokens, &logits)?;
             let probabilities = softmax_one(&self.context, logits).await?;
-            next_input = sampler.sample(settings, &probabilities);
+            next_input = sampler.sample(&settings.sampler, &probabilities);
 
             // Accumulate newly generated tokens
             generated.push(next_input);
 
+            if let (Some(logprobs), Some(top)) = (&mut logprobs, settings.logprobs) {
+                logprobs.push(self.token_logprob(next_input, &probabilities, top)?);
+            }
+
             sampler.consume_token(next_input);
+
+            // Stop strings can span token boundaries, so they have to be checked on decoded text
+            let bytes = self.tokenizer.decode(&[next_input])?;
+            decoder.push(format, &generated, &bytes);
+            if decoder.find_stop_string(&settings.stop).is_some() {
+                break StopReason::StopString;
+            }
+
+            // Stream out any content we're sure about
+            let content = decoder.content();
+            let streamable = content.len() - stop_string_prefix_len(&settings.stop, content);
+            if let Some(delta) = content.get(streamed..streamable).filter(|v| !v.is_empty()) {
+                on_content(delta);
+                streamed = streamable;
+            }
+        };
+
+        let completion_tokens = generated.len();
+        let decode_time = started.elapsed() - prefill_time;
+        let (content, stopped) = self.finalize_generated(format, &settings.stop, &mut generated)?;
+        if stopped {
+            stop_reason = StopReason::StopString;
         }
 
-        let content = self.finalize_generated(generated)?;
+        // Only report logprobs of tokens that are part of the returned content
+        if let Some(logprobs) = &mut logprobs {
+            logprobs.truncate(generated.len());
+        }
+
+        // Flush anything that was still held back
+        if let Some(delta) = content.get(streamed..).filter(|v| !v.is_empty()) {
+            on_content(delta);
+        }
+
+        let value = Generation {
+            content,
+            prompt_tokens,
+            completion_tokens,
+            stop_reason,
+            logprobs,
+            prefill_time,
+            decode_time,
+        };
 
-        Ok(content)
+        Ok(value)
     }
 
-    fn should_stop_generation(&self, max_tokens: usize, tokens: &[u16]) -> bool {
-        // Maximum tokens
-        if tokens.len() >= max_tokens {
-            return true;
+    /// Fails with `AgentError::Cancelled` if `cancel` is cancelled before all tokens are processed.
+    async fn process_tokens(
+        &self,
+        tokens: Vec<u16>,
+        cancel: &CancellationToken,
+    ) -> Result<(), Error> {
+        if tokens.is_empty() {
+            return Ok(());
         }
 
-        // Ending with stop tokens
-        if tokens.ends_with(&self.config.stop_sequence) {
-            return true;
-        }
+        // Prefill is batched with other slots too, the logits aren't needed
+        self.batcher.infer(self.index, tokens, cancel).await?;
 
-        false
+        Ok(())
     }
+}
 
-    fn finalize_generated(&self, mut tokens: Vec<u16>) -> Result<String, Error> {
-        // Trim stop tokens, if we got them at the end
-        if tokens.ends_with(&self.config.stop_sequence) {
-            for _ in 0..self.config.stop_sequence.len() {
-                tokens.pop();
+impl Deref for ModelSlot {
+    type Target = ActiveModel;
+
+    fn deref(&self) -> &ActiveModel {
+        &self.model
+    }
+}
+
+impl Drop for ModelSlot {
+    fn drop(&mut self) {
+        self.model.batcher.release(self.index);
+
+        let mut free_slots = self.model.free_slots.lock().unwrap();
+        free_slots.push(self.index);
+    }
+}
+
+/// Settings controlling how output is generated.
+#[derive(Clone)]
+pub struct GenerateSettings {
+    pub max_tokens: usize,
+    /// Strings that end generation when they appear in the output, trimmed from the result.
+    pub stop: Vec<String>,
+    /// If set, report log probabilities of generated tokens, with this many top alternatives.
+    pub logprobs: Option<usize>,
+    pub sampler: SamplerSettings,
+    /// Checked between steps, stops generation when cancelled.
+    pub cancel: CancellationToken,
+}
+
+/// Result of generating output from the model.
+pub struct Generation {
+    pub content: String,
+    /// Prompt tokens processed as part of generation, such as the assistant prefix.
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub stop_reason: StopReason,
+    pub logprobs: Option<Vec<TokenLogprob>>,
+    /// Time spent processing the prompt tokens.
+    pub prefill_time: Duration,
+    /// Time spent generating the completion tokens.
+    pub decode_time: Duration,
+}
+
+/// Why generation stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The model generated the stop sequence of its prompt format.
+    StopSequence,
+    /// The maximum amount of tokens was reached.
+    MaxTokens,
+    /// The output contained a stop string given by the client.
+    StopString,
+    /// Generation was cancelled before it finished.
+    Cancelled,
+}
+
+/// How generated tokens should be turned into output content.
+struct OutputFormat<'a> {
+    stop_sequence: &'a [u16],
+    trim_leading_space: bool,
+}
+
+impl OutputFormat<'_> {
+    fn bytes_to_content(&self, bytes: &[u8]) -> String {
+        let content = String::from_utf8_lossy(bytes);
+
+        // Typically the model will output the first token with a prefixing space, remove that
+        if self.trim_leading_space {
+            if let Some(value) = content.strip_prefix(' ') {
+                return value.to_string();
             }
         }
 
-        // Decode the tokenized answer
-        let answer_bytes = self.tokenizer.decode(&tokens)?;
-        let answer = String::from_utf8_lossy(&answer_bytes);
+        content.to_string()
+    }
+}
 
-        // Typically the model will output the first token with a prefixing space, remove that
-        let value = if let Some(value) = answer.strip_prefix(' ') {
-            value.to_string()
+/// Decodes the part of a message still being generated that can't change anymore.
+///
+/// Tokens that may be the start of the stop sequence, and incomplete UTF-8 characters, are held
+/// back until we know more. Content is decoded as it comes in, so every token only costs the
+/// work for its own bytes.
+#[derive(Default)]
+struct PartialDecoder {
+    /// Bytes of every generated token.
+    bytes: Vec<u8>,
+    /// Amount of bytes every generated token decoded to.
+    token_lens: Vec<usize>,
+    /// Amount of `bytes` already decoded into `content`.
+    decoded: usize,
+    content: String,
+    /// Amount of `content` already searched for stop strings.
+    searched: usize,
+}
+
+impl PartialDecoder {
+    /// Add the bytes of the last token in `tokens`, decoding whatever is certain now.
+    fn push(&mut self, format: &OutputFormat, tokens: &[u16], bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+        self.token_lens.push(bytes.len());
+
+        // What's held back only ever grows by the new token, so decoding never has to go back
+        let stop_sequence = format.stop_sequence;
+        let held = (1..=stop_sequence.len().min(tokens.len()))
+            .rev()
+            .find(|&length| tokens.ends_with(&stop_sequence[..length]))
+            .unwrap_or(0);
+        let held_bytes: usize = self.token_lens.iter().rev().take(held).sum();
+
+        let available = &self.bytes[..self.bytes.len() - held_bytes];
+        let end = complete_utf8_len(available);
+        if end <= self.decoded {
+            return;
+        }
+
+        let chunk = &self.bytes[self.decoded..end];
+        if self.decoded == 0 {
+            self.content = format.bytes_to_content(chunk);
         } else {
-            answer.to_string()
-        };
+            self.content.push_str(&String::from_utf8_lossy(chunk));
+        }
+        self.decoded = end;
+    }
 
-        Ok(value)
+    fn content(&self) -> &str {
+        &self.content
     }
 
-    async fn process_tokens(&self, tokens: Vec<u16>) -> Result<(), Error> {
-        // Process initial prompt
-        let batch = InferInputBatch {
-            tokens,
-            option: InferOption::Last,
-        };
-        let mut input = InferInput::new(vec![batch], 32);
+    /// Find the earliest stop string in the content, only searching what could contain a new one.
+    fn find_stop_string(&mut self, stop: &[String]) -> Option<usize> {
+        let longest = stop.iter().map(String::len).max().unwrap_or(0);
+        let mut start = self.searched.saturating_sub(longest.saturating_sub(1));
+        while !self.content.is_char_boundary(start) {
+            start -= 1;
+        }
+        self.searched = self.content.len();
+
+        find_stop_string(stop, &self.content[start..]).map(|position| start + position)
+    }
+}
+
+fn should_stop_generation(
+    format: &OutputFormat,
+    max_tokens: usize,
+    tokens: &[u16],
+) -> Option<StopReason> {
+    // Ending with stop tokens, checked first as it takes precedence if both happen at once
+    if !format.stop_sequence.is_empty() && tokens.ends_with(format.stop_sequence) {
+        return Some(StopReason::StopSequence);
+    }
 
-        while !input.batches[0].tokens.is_empty() {
-            let (out_input, _output) = self.runtime.infer(input).await;
-            input = out_input;
+    // Maximum tokens
+    if tokens.len() >= max_tokens {
+        return Some(StopReason::MaxTokens);
+    }
+
+    None
+}
+
+/// Indices of the `count` most likely tokens, most likely first.
+fn most_likely_tokens(probabilities: &[f32], count: usize) -> Vec<u16> {
+    let count = count.min(probabilities.len());
+    if count == 0 {
+        return Vec::new();
+    }
+
+    let compare = |a: &usize, b: &usize| probabilities[*b].total_cmp(&probabilities[*a]);
+    let mut indices: Vec<usize> = (0..probabilities.len()).collect();
+    indices.select_nth_unstable_by(count - 1, compare);
+    indices.truncate(count);
+    indices.sort_by(compare);
+
+    indices.into_iter().map(|index| index as u16).collect()
+}
+
+/// Find the earliest position at which any of the stop strings occurs in the content.
+fn find_stop_string(stop: &[String], content: &str) -> Option<usize> {
+    stop.iter()
+        .filter(|value| !value.is_empty())
+        .filter_map(|value| content.find(value.as_str()))
+        .min()
+}
+
+/// Length of the longest end of the content that could be the start of a stop string.
+fn stop_string_prefix_len(stop: &[String], content: &str) -> usize {
+    let content = content.as_bytes();
+
+    stop.iter()
+        .filter_map(|value| {
+            let value = value.as_bytes();
+            (1..value.len().min(content.len() + 1))
+                .rev()
+                .find(|&length| content.ends_with(&value[..length]))
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Length of `bytes`, excluding a trailing UTF-8 character that isn't complete yet.
+fn complete_utf8_len(bytes: &[u8]) -> usize {
+    for (offset, &byte) in bytes.iter().rev().take(4).enumerate() {
+        // Skip continuation bytes until we find the start of the character
+        if byte & 0b1100_0000 == 0b1000_0000 {
+            continue;
         }
 
-        Ok(())
+        let expected = match byte.leading_ones() {
+            2 => 2,
+            3 => 3,
+            4 => 4,
+            _ => 1,
+        };
+        let start = bytes.len() - offset - 1;
+
+        return if bytes.len() - start < expected {
+            start
+        } else {
+            bytes.len()
+        };
     }
+
+    bytes.len()
+}
+
+/// Weights file details, shown in model metadata.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct WeightsMetadata {
+    /// Modification time of the file, in seconds since the unix epoch.
+    pub created: u64,
+    /// Size of the file in bytes.
+    pub size: u64,
+}
+
+pub(crate) fn read_weights_metadata(path: &Path) -> Result<WeightsMetadata, Error> {
+    let metadata = std::fs::metadata(path)
+        .with_context(|| format!("failed to read metadata of {}", path.display()))?;
+
+    let created = metadata
+        .modified()?
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+
+    let value = WeightsMetadata {
+        created,
+        size: metadata.len(),
+    };
+    Ok(value)
 }
 
 async fn load_model(
     version: ModelVersion,
-    path: &str,
-    quant_nf8: bool,
+    path: &Path,
+    quantization: Quantization,
+    num_slots: usize,
 ) -> Result<
     (
         Context,
         JobRuntime<InferInput, InferOutput>,
-        Box<dyn State + Send + Sync>,
+        Arc<dyn State + Send + Sync>,
+        LoaderInfo,
     ),
     Error,
 > {
-    event!(Level::INFO, path, "loading model");
+    event!(Level::INFO, path = %path.display(), "loading model");
 
     // Preload the model
     let file = File::open(path)?;
@@ -255,8 +913,11 @@ async fn load_model(
         .build()
         .await?;
 
-    // Quantize all layers to 8-bit
-    let quant = if quant_nf8 { Quant::NF4 } else { Quant::Int8 };
+    // Quantize all layers
+    let quant = match quantization {
+        Quantization::Int8 => Quant::Int8,
+        Quantization::Nf4 => Quant::NF4,
+    };
     let quantize = (0..model_info.num_layer)
         .map(|layer| (layer, quant))
         .collect();
@@ -265,34 +926,155 @@ async fn load_model(
     let builder = ModelBuilder::new(&context, safetensors).quant(quantize);
 
     // Build the runtime, actually loading weights
-    let (runtime, state): (_, Box<dyn State + Send + Sync>) = match version {
+    // Every slot gets its own batch in the runtime
+    let (runtime, state): (_, Arc<dyn State + Send + Sync>) = match version {
         ModelVersion::V4 => {
             event!(Level::INFO, "loading rwkv-v4 model");
             let model = Build::<v4::Model>::build(builder).await?;
-            let builder = v4::ModelRuntime::<f16>::new(model, 1);
+            let builder = v4::ModelRuntime::<f16>::new(model, num_slots);
             let state = builder.state();
             let runtime = JobRuntime::new(builder).await;
-            (runtime, Box::new(state))
+            (runtime, Arc::new(state))
         }
         ModelVersion::V5 => {
             event!(Level::INFO, "loading rwkv-v5 model");
             let model = Build::<v5::Model>::build(builder).await?;
-            let builder = v5::ModelRuntime::<f16>::new(model, 1);
+            let builder = v5::ModelRuntime::<f16>::new(model, num_slots);
             let state = builder.state();
             let runtime = JobRuntime::new(builder).await;
-            (runtime, Box::new(state))
+            (runtime, Arc::new(state))
         }
         ModelVersion::V6 => {
             event!(Level::INFO, "loading rwkv-v6 model");
             let model = Build::<v6::Model>::build(builder).await?;
-            let builder = v6::ModelRuntime::<f16>::new(model, 1);
+            let builder = v6::ModelRuntime::<f16>::new(model, num_slots);
             let state = builder.state();
             let runtime = JobRuntime::new(builder).await;
-            (runtime, Box::new(state))
+            (runtime, Arc::new(state))
         }
     };
 
     event!(Level::INFO, "finished loading model");
 
-    Ok((context, runtime, state))
+    Ok((context, runtime, state, model_info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMAT: OutputFormat = OutputFormat {
+        stop_sequence: &[0, 1],
+        trim_leading_space: true,
+    };
+
+    fn stop(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    /// Push tokens into a decoder one at a time, as generation would.
+    fn decode(decoder: &mut PartialDecoder, tokens: &mut Vec<u16>, pieces: &[(u16, &[u8])]) {
+        for (token, bytes) in pieces {
+            tokens.push(*token);
+            decoder.push(&FORMAT, tokens, bytes);
+        }
+    }
+
+    #[test]
+    fn find_stop_string_picks_earliest_of_overlapping() {
+        let values = stop(&["bcd", "abc", "c"]);
+        assert_eq!(find_stop_string(&values, "xxabcd"), Some(2));
+
+        let values = stop(&["cd", "bcd"]);
+        assert_eq!(find_stop_string(&values, "xxabcd"), Some(3));
+
+        let values = stop(&["", "zz"]);
+        assert_eq!(find_stop_string(&values, "xxabcd"), None);
+    }
+
+    #[test]
+    fn stop_string_prefix_len_finds_longest_partial_match() {
+        let values = stop(&["\nUser:"]);
+        assert_eq!(stop_string_prefix_len(&values, "Hello\nUs"), 3);
+        assert_eq!(stop_string_prefix_len(&values, "Hello"), 0);
+
+        // Overlapping stop strings hold back as much as the longest possible match needs
+        let values = stop(&["ab", "abab"]);
+        assert_eq!(stop_string_prefix_len(&values, "xaba"), 3);
+        assert_eq!(stop_string_prefix_len(&values, "xab"), 2);
+    }
+
+    #[test]
+    fn complete_utf8_len_excludes_incomplete_character() {
+        assert_eq!(complete_utf8_len(b"abc"), 3);
+        assert_eq!(complete_utf8_len("aé".as_bytes()), 3);
+        assert_eq!(complete_utf8_len(&"aé".as_bytes()[..2]), 1);
+
+        let emoji = "a🦅".as_bytes();
+        for end in 2..emoji.len() {
+            assert_eq!(complete_utf8_len(&emoji[..end]), 1);
+        }
+        assert_eq!(complete_utf8_len(emoji), emoji.len());
+    }
+
+    #[test]
+    fn decoder_finds_stop_string_split_across_tokens() {
+        let values = stop(&["\nUser:"]);
+        let mut decoder = PartialDecoder::default();
+        let mut tokens = Vec::new();
+
+        decode(&mut decoder, &mut tokens, &[(10, b" Hel"), (11, b"lo\nUs")]);
+        assert_eq!(decoder.content(), "Hello\nUs");
+        assert_eq!(decoder.find_stop_string(&values), None);
+
+        decode(&mut decoder, &mut tokens, &[(12, b"er: hi")]);
+        assert_eq!(decoder.find_stop_string(&values), Some(5));
+    }
+
+    #[test]
+    fn decoder_finds_earliest_of_overlapping_stop_strings() {
+        let values = stop(&["cde", "bcdef"]);
+        let mut decoder = PartialDecoder::default();
+        let mut tokens = Vec::new();
+
+        decode(&mut decoder, &mut tokens, &[(10, b"ab"), (11, b"cd")]);
+        assert_eq!(decoder.find_stop_string(&values), None);
+
+        decode(&mut decoder, &mut tokens, &[(12, b"ef")]);
+        assert_eq!(decoder.find_stop_string(&values), Some(1));
+    }
+
+    #[test]
+    fn decoder_holds_back_split_multibyte_character() {
+        let eagle = "🦅".as_bytes();
+        let mut decoder = PartialDecoder::default();
+        let mut tokens = Vec::new();
+
+        decode(&mut decoder, &mut tokens, &[(10, b"a"), (11, &eagle[..1])]);
+        assert_eq!(decoder.content(), "a");
+
+        decode(&mut decoder, &mut tokens, &[(12, &eagle[1..3])]);
+        assert_eq!(decoder.content(), "a");
+
+        decode(&mut decoder, &mut tokens, &[(13, &eagle[3..])]);
+        assert_eq!(decoder.content(), "a🦅");
+    }
+
+    #[test]
+    fn decoder_releases_stop_sequence_prefix_that_does_not_match() {
+        let mut decoder = PartialDecoder::default();
+        let mut tokens = Vec::new();
+
+        // The first stop token is held back, as it could be the start of the stop sequence
+        decode(&mut decoder, &mut tokens, &[(10, b" a"), (0, b"\n\n")]);
+        assert_eq!(decoder.content(), "a");
+
+        // It isn't followed by the rest of the sequence, so it's part of the content after all
+        decode(&mut decoder, &mut tokens, &[(11, b"b")]);
+        assert_eq!(decoder.content(), "a\n\nb");
+
+        // The full stop sequence never becomes content
+        decode(&mut decoder, &mut tokens, &[(0, b"\n\n"), (1, b"User")]);
+        assert_eq!(decoder.content(), "a\n\nb");
+    }
 }
@@ -0,0 +1,387 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use anyhow::{Context as _, Error};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{event, Level};
+use web_rwkv::{
+    runtime::{
+        infer::{InferInput, InferInputBatch, InferOption, InferOutput},
+        model::State,
+        JobRuntime,
+    },
+    tensor::TensorCpu,
+};
+
+use crate::error::AgentError;
+
+/// How long a step waits for slots that were in the previous step to send their next token.
+///
+/// Slots sample their next token in between steps, without this they'd drift apart and take
+/// turns running steps on their own.
+const SYNC_TIMEOUT: Duration = Duration::from_millis(20);
+
+/// Runs the model for all slots together, so concurrent requests share the same `infer` calls.
+///
+/// Every slot owns one batch of the runtime's state. State is only ever touched by the batch
+/// task, in between inference steps, so a slot can never be loaded while it's being run.
+#[derive(Clone)]
+pub(crate) struct Batcher {
+    sender: mpsc::UnboundedSender<Job>,
+}
+
+/// The model runtime and state the batch task works on.
+pub(crate) trait Runtime: Send + Sync + 'static {
+    fn num_slots(&self) -> usize;
+
+    /// Run one step, processing up to one chunk of tokens across all batches.
+    fn infer(&self, input: InferInput) -> impl Future<Output = (InferInput, InferOutput)> + Send;
+
+    fn load(&self, slot: usize, state: TensorCpu<f32>) -> Result<(), Error>;
+
+    fn back(&self, slot: usize) -> impl Future<Output = Result<TensorCpu<f32>, Error>> + Send;
+}
+
+pub(crate) struct RwkvRuntime {
+    pub runtime: JobRuntime<InferInput, InferOutput>,
+    pub state: Arc<dyn State + Send + Sync>,
+}
+
+impl Runtime for RwkvRuntime {
+    fn num_slots(&self) -> usize {
+        self.state.num_batch()
+    }
+
+    async fn infer(&self, input: InferInput) -> (InferInput, InferOutput) {
+        self.runtime.infer(input).await
+    }
+
+    fn load(&self, slot: usize, state: TensorCpu<f32>) -> Result<(), Error> {
+        self.state.load(state, slot).map_err(Error::from)
+    }
+
+    async fn back(&self, slot: usize) -> Result<TensorCpu<f32>, Error> {
+        self.state.back(slot).await.map_err(Error::from)
+    }
+}
+
+enum Job {
+    Infer {
+        slot: usize,
+        tokens: Vec<u16>,
+        cancel: CancellationToken,
+        sender: oneshot::Sender<Result<TensorCpu<f32>, Error>>,
+    },
+    Load {
+        slot: usize,
+        state: TensorCpu<f32>,
+        sender: oneshot::Sender<Result<(), Error>>,
+    },
+    Back {
+        slot: usize,
+        sender: oneshot::Sender<Result<TensorCpu<f32>, Error>>,
+    },
+    Acquire {
+        slot: usize,
+    },
+    Release {
+        slot: usize,
+    },
+}
+
+impl Batcher {
+    pub fn start(runtime: impl Runtime) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::task::spawn(run_batches(runtime, receiver));
+
+        Self { sender }
+    }
+
+    /// Process tokens into a slot's state, returning the logits after the last token.
+    ///
+    /// Once `cancel` is cancelled, the remaining tokens are dropped in between steps, and this
+    /// fails with `AgentError::Cancelled`.
+    pub async fn infer(
+        &self,
+        slot: usize,
+        tokens: Vec<u16>,
+        cancel: &CancellationToken,
+    ) -> Result<TensorCpu<f32>, Error> {
+        let (sender, receiver) = oneshot::channel();
+        let job = Job::Infer {
+            slot,
+            tokens,
+            cancel: cancel.clone(),
+            sender,
+        };
+        self.sender.send(job).ok().context("batch task stopped")?;
+
+        receiver.await.context("batch task stopped")?
+    }
+
+    pub async fn load(&self, slot: usize, state: TensorCpu<f32>) -> Result<(), Error> {
+        let (sender, receiver) = oneshot::channel();
+        let job = Job::Load {
+            slot,
+            state,
+            sender,
+        };
+        self.sender.send(job).ok().context("batch task stopped")?;
+
+        receiver.await.context("batch task stopped")?
+    }
+
+    pub async fn back(&self, slot: usize) -> Result<TensorCpu<f32>, Error> {
+        let (sender, receiver) = oneshot::channel();
+        let job = Job::Back { slot, sender };
+        self.sender.send(job).ok().context("batch task stopped")?;
+
+        receiver.await.context("batch task stopped")?
+    }
+
+    /// Mark a slot as held by a request, so steps wait for its tokens.
+    pub fn acquire(&self, slot: usize) {
+        let _ = self.sender.send(Job::Acquire { slot });
+    }
+
+    /// Mark a slot as no longer held, steps stop waiting for it.
+    pub fn release(&self, slot: usize) {
+        let _ = self.sender.send(Job::Release { slot });
+    }
+}
+
+#[derive(Default)]
+struct Slot {
+    /// The `infer` call waiting for this slot's tokens to be processed.
+    pending: Option<Pending>,
+    /// Held by a request.
+    active: bool,
+    /// Just acquired or was in the last step, and will likely send tokens soon.
+    expected: bool,
+}
+
+impl Slot {
+    fn is_waited_for(&self) -> bool {
+        self.active && self.expected && self.pending.is_none()
+    }
+}
+
+/// An `infer` call waiting for its slot's tokens to be processed.
+struct Pending {
+    cancel: CancellationToken,
+    sender: oneshot::Sender<Result<TensorCpu<f32>, Error>>,
+}
+
+/// Stops once every `Batcher` handle is dropped and all work is done.
+async fn run_batches(runtime: impl Runtime, mut receiver: mpsc::UnboundedReceiver<Job>) {
+    let num_slots = runtime.num_slots();
+    let mut batches = vec![InferInputBatch::default(); num_slots];
+    let mut slots: Vec<Slot> = (0..num_slots).map(|_| Slot::default()).collect();
+
+    loop {
+        // Wait for work if we're idle, then pick up anything else that came in meanwhile
+        if slots.iter().all(|slot| slot.pending.is_none()) {
+            let Some(job) = receiver.recv().await else {
+                break;
+            };
+            accept_job(&runtime, &mut batches, &mut slots, job).await;
+        }
+        while let Ok(job) = receiver.try_recv() {
+            accept_job(&runtime, &mut batches, &mut slots, job).await;
+        }
+
+        // Give slots that are still sampling a moment to catch up, so they share this step
+        let deadline = Instant::now() + SYNC_TIMEOUT;
+        while slots.iter().any(Slot::is_waited_for) {
+            match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(job)) => accept_job(&runtime, &mut batches, &mut slots, job).await,
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        // Don't spend time on slots nobody is waiting for anymore
+        for (batch, slot) in batches.iter_mut().zip(slots.iter_mut()) {
+            slot.expected = false;
+            let Some(pending) = &slot.pending else {
+                continue;
+            };
+
+            if pending.sender.is_closed() {
+                batch.tokens.clear();
+                slot.pending = None;
+            } else if pending.cancel.is_cancelled() {
+                batch.tokens.clear();
+                if let Some(pending) = slot.pending.take() {
+                    let _ = pending.sender.send(Err(AgentError::Cancelled.into()));
+                }
+            }
+        }
+        if slots.iter().all(|slot| slot.pending.is_none()) {
+            continue;
+        }
+
+        // Run one step for all slots with pending tokens
+        let input = InferInput::new(batches, 32);
+        let (input, output) = runtime.infer(input).await;
+        batches = input.batches;
+
+        // Send back the output of every slot that's done
+        for (index, (batch, slot)) in batches.iter().zip(slots.iter_mut()).enumerate() {
+            if !batch.tokens.is_empty() {
+                continue;
+            }
+
+            if let Some(pending) = slot.pending.take() {
+                let _ = pending.sender.send(Ok(output[index].0.clone()));
+                slot.expected = true;
+            }
+        }
+    }
+
+    event!(Level::DEBUG, "batch task stopped");
+}
+
+async fn accept_job(
+    runtime: &impl Runtime,
+    batches: &mut [InferInputBatch],
+    slots: &mut [Slot],
+    job: Job,
+) {
+    match job {
+        Job::Infer {
+            slot,
+            tokens,
+            cancel,
+            sender,
+        } => {
+            batches[slot] = InferInputBatch {
+                tokens,
+                option: InferOption::Last,
+            };
+            slots[slot].pending = Some(Pending { cancel, sender });
+        }
+        Job::Load {
+            slot,
+            state: value,
+            sender,
+        } => {
+            // Anything left over from a previous user of the slot is stale now
+            batches[slot].tokens.clear();
+            slots[slot].pending = None;
+
+            let _ = sender.send(runtime.load(slot, value));
+        }
+        Job::Back { slot, sender } => {
+            let _ = sender.send(runtime.back(slot).await);
+        }
+        Job::Acquire { slot } => {
+            slots[slot].active = true;
+            slots[slot].expected = true;
+        }
+        Job::Release { slot } => {
+            slots[slot].active = false;
+            slots[slot].expected = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use web_rwkv::{
+        runtime::{infer::InferOutputBatch, JobInput},
+        tensor::TensorInit,
+    };
+
+    use super::*;
+
+    /// Runtime that only records which slots took part in every step.
+    #[derive(Clone, Default)]
+    struct FakeRuntime {
+        steps: Arc<Mutex<Vec<Vec<usize>>>>,
+    }
+
+    impl Runtime for FakeRuntime {
+        fn num_slots(&self) -> usize {
+            2
+        }
+
+        async fn infer(&self, mut input: InferInput) -> (InferInput, InferOutput) {
+            let running = input
+                .batches
+                .iter()
+                .enumerate()
+                .filter(|(_, batch)| !batch.tokens.is_empty())
+                .map(|(slot, _)| slot)
+                .collect();
+            self.steps.lock().unwrap().push(running);
+
+            // Pretend running the model takes a while
+            tokio::time::sleep(Duration::from_millis(2)).await;
+            input.step();
+
+            let output = (0..input.batches.len())
+                .map(|_| InferOutputBatch(TensorCpu::from_data([1, 1, 1, 1], vec![0.0]).unwrap()))
+                .collect();
+            (input, InferOutput(output))
+        }
+
+        fn load(&self, _slot: usize, _state: TensorCpu<f32>) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn back(&self, _slot: usize) -> Result<TensorCpu<f32>, Error> {
+            Ok(TensorCpu::from_data([1, 1, 1, 1], vec![0.0])?)
+        }
+    }
+
+    /// Decode like a request holding `slot` would, taking `sampling` to pick every next token.
+    async fn decode(batcher: Batcher, slot: usize, sampling: Duration) {
+        let cancel = CancellationToken::new();
+        for _ in 0..8 {
+            batcher.infer(slot, vec![0], &cancel).await.unwrap();
+            tokio::time::sleep(sampling).await;
+        }
+        batcher.release(slot);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn decoding_slots_share_steps() {
+        let runtime = FakeRuntime::default();
+        let batcher = Batcher::start(runtime.clone());
+        batcher.acquire(0);
+        batcher.acquire(1);
+
+        let a = tokio::spawn(decode(batcher.clone(), 0, Duration::from_millis(1)));
+        let b = tokio::spawn(decode(batcher.clone(), 1, Duration::from_millis(5)));
+        a.await.unwrap();
+        b.await.unwrap();
+
+        let steps = runtime.steps.lock().unwrap();
+        assert_eq!(steps.len(), 8);
+        assert!(steps.iter().all(|slots| *slots == [0, 1]));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn released_slots_are_not_waited_for() {
+        let runtime = FakeRuntime::default();
+        let batcher = Batcher::start(runtime.clone());
+        let cancel = CancellationToken::new();
+
+        batcher.acquire(0);
+        batcher.infer(0, vec![0], &cancel).await.unwrap();
+        batcher.release(0);
+
+        // Slot 0 is gone, so slot 1 shouldn't have to wait for it
+        batcher.acquire(1);
+        let started = Instant::now();
+        batcher.infer(1, vec![0], &cancel).await.unwrap();
+        assert!(started.elapsed() < SYNC_TIMEOUT);
+
+        assert_eq!(*runtime.steps.lock().unwrap(), [vec![0], vec![1]]);
+    }
+}
@@ -1,12 +1,46 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+};
 
-use anyhow::{Context, Error};
+use anyhow::{bail, Context, Error};
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
-pub(crate) fn load_model_configs() -> Result<HashMap<String, ModelConfig>, Error> {
+/// Load the global configuration, using defaults if the file doesn't exist.
+///
+/// The result isn't validated yet, as overrides still have to be applied on top.
+pub fn load_config(path: &Path) -> Result<Config, Error> {
+    if !path.exists() {
+        return Ok(Config::default());
+    }
+
+    let config_str = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let value: Config = toml::from_str(&config_str)
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+
+    Ok(value)
+}
+
+/// Normalize a URL prefix to either be empty, or start and not end with a slash.
+fn normalize_base_path(value: &str) -> Result<String, Error> {
+    let value = value.trim_end_matches('/');
+
+    if !value.is_empty() && !value.starts_with('/') {
+        bail!("base path {:?} must start with a slash", value);
+    }
+
+    Ok(value.to_string())
+}
+
+pub(crate) fn load_model_configs(models_dir: &Path) -> Result<HashMap<String, ModelConfig>, Error> {
     let mut model_configs = HashMap::new();
 
-    for entry in std::fs::read_dir("./data")? {
+    let entries = std::fs::read_dir(models_dir)
+        .with_context(|| format!("failed to read models directory {}", models_dir.display()))?;
+    for entry in entries {
         let entry = entry?;
 
         let path = entry.path();
@@ -20,8 +54,9 @@ pub(crate) fn load_model_configs() -> Result<HashMap<String, ModelConfig>, Error
         let name = name.to_string_lossy().to_string();
 
         // Parse the file contents
-        let config_str = std::fs::read_to_string(path)?;
-        let value: ModelConfig = toml::from_str(&config_str)?;
+        let config_str = std::fs::read_to_string(&path)?;
+        let value: ModelConfig = toml::from_str(&config_str)
+            .with_context(|| format!("failed to parse {}", path.display()))?;
 
         model_configs.insert(name, value);
     }
@@ -29,15 +64,314 @@ pub(crate) fn load_model_configs() -> Result<HashMap<String, ModelConfig>, Error
     Ok(model_configs)
 }
 
+/// Global configuration, from "minmodmon.toml".
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub paths: PathsConfig,
+    pub models: ModelsConfig,
+    pub defaults: DefaultsConfig,
+    pub limits: LimitsConfig,
+    pub queue: QueueConfig,
+    pub auth: AuthConfig,
+    pub logging: LoggingConfig,
+}
+
+impl Config {
+    /// Check the configuration makes sense, after all overrides have been applied.
+    pub fn validate(&mut self) -> Result<(), Error> {
+        if self.server.addresses.is_empty() && self.server.unix_socket.is_none() {
+            bail!("server.addresses can't be empty, unless server.unix_socket is set");
+        }
+        if self.server.unix_socket.is_some() && !cfg!(unix) {
+            bail!("server.unix_socket is only supported on unix platforms");
+        }
+        if self
+            .server
+            .unix_socket_mode
+            .is_some_and(|mode| mode > 0o777)
+        {
+            bail!("server.unix_socket_mode must be a permission mode like 0o660");
+        }
+        self.server.base_path =
+            normalize_base_path(&self.server.base_path).context("invalid server.base_path")?;
+
+        if self.models.slots == 0 {
+            bail!("models.slots must be at least 1");
+        }
+        if self.queue.max_pending == 0 {
+            bail!("queue.max_pending must be at least 1");
+        }
+        if self.limits.max_tokens == 0 || self.limits.max_choices == 0 {
+            bail!("limits must be at least 1");
+        }
+        if self.defaults.max_tokens > self.limits.max_tokens {
+            bail!("defaults.max_tokens can't be above limits.max_tokens");
+        }
+        if self.auth.keys.iter().any(|key| key.key.is_empty()) {
+            bail!("auth.keys can't contain empty keys");
+        }
+        // Requests belong to the name of the key they were made with
+        if !self.auth.keys.iter().map(|key| &key.name).all_unique() {
+            bail!("auth.keys can't contain duplicate names");
+        }
+        if !self.auth.keys.iter().map(|key| &key.key).all_unique() {
+            bail!("auth.keys can't contain duplicate keys");
+        }
+
+        for (name, path) in [
+            ("paths.data_dir", &self.paths.data_dir),
+            ("paths.models_dir", &self.paths.models_dir),
+        ] {
+            if !path.is_dir() {
+                bail!("{} {:?} is not a directory", name, path);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Addresses to listen on, all on the same port.
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+    /// Prefix of all URLs, for example "/minmodmon" when running behind a reverse proxy.
+    pub base_path: String,
+    /// URL the server is reachable at from outside, shown in the dashboard.
+    pub public_url: Option<String>,
+    /// Unix domain socket to listen on, alongside or instead of the addresses.
+    pub unix_socket: Option<PathBuf>,
+    /// Permissions of the unix socket, for example 0o660, by default the umask decides.
+    pub unix_socket_mode: Option<u32>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            addresses: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            port: 5000,
+            base_path: String::new(),
+            public_url: None,
+            unix_socket: None,
+            unix_socket_mode: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathsConfig {
+    /// Dashboard templates and assets.
+    pub data_dir: PathBuf,
+    /// Model configs, along with their weights.
+    pub models_dir: PathBuf,
+}
+
+impl Default for PathsConfig {
+    fn default() -> Self {
+        Self {
+            data_dir: PathBuf::from("./data"),
+            models_dir: PathBuf::from("./data"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelsConfig {
+    /// Model to load when the server starts.
+    pub startup_model: Option<String>,
+    /// Load models requested by clients that aren't currently loaded.
+    pub auto_load: bool,
+    /// How long a request waits for its model to load, in seconds.
+    pub auto_load_timeout: u64,
+    /// How many requests a model runs at the same time, batched together.
+    pub slots: usize,
+}
+
+impl Default for ModelsConfig {
+    fn default() -> Self {
+        Self {
+            startup_model: None,
+            auto_load: false,
+            auto_load_timeout: 300,
+            slots: 4,
+        }
+    }
+}
+
+/// Values used when a request doesn't specify them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DefaultsConfig {
+    pub max_tokens: usize,
+    pub temperature: f32,
+    pub presence_penalty: f32,
+    pub frequency_penalty: f32,
+    /// Quantization of models loaded at startup or on request.
+    pub quantization: Quantization,
+}
+
+impl Default for DefaultsConfig {
+    fn default() -> Self {
+        Self {
+            max_tokens: 512,
+            temperature: 0.8,
+            presence_penalty: 0.3,
+            frequency_penalty: 0.3,
+            quantization: Quantization::Int8,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Quantization {
+    Int8,
+    Nf4,
+}
+
+impl Quantization {
+    /// Parse the lowercase name used in configs and requests.
+    pub fn from_name(value: &str) -> Option<Self> {
+        match value {
+            "int8" => Some(Self::Int8),
+            "nf4" => Some(Self::Nf4),
+            _ => None,
+        }
+    }
+}
+
+/// Upper bounds on what a single request can ask for.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_tokens: usize,
+    /// Maximum alternative choices generated for one chat request.
+    pub max_choices: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_tokens: 4096,
+            max_choices: 8,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    /// How many requests can wait for the model, before new ones are rejected.
+    pub max_pending: usize,
+    /// Seconds a rejected client is told to wait before retrying.
+    pub retry_after: u64,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            max_pending: 16,
+            retry_after: 5,
+        }
+    }
+}
+
+/// API keys clients have to authenticate with, if none are configured anyone has full access.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub keys: Vec<ApiKeyConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyConfig {
+    /// Who the key belongs to, has to be unique. Requests made with the key belong to this name.
+    pub name: String,
+    pub key: String,
+    pub scope: KeyScope,
+}
+
+/// What a key gives access to, admin keys can do everything inference keys can.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyScope {
+    /// Generating output from the loaded model.
+    Inference,
+    /// Loading models and managing the server, including through the dashboard.
+    Admin,
+}
+
+impl AuthConfig {
+    pub fn enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// Find the configured key matching the one a client gave.
+    pub fn find_key(&self, key: &str) -> Option<&ApiKeyConfig> {
+        self.keys
+            .iter()
+            .find(|value| constant_time_eq(value.key.as_bytes(), key.as_bytes()))
+    }
+}
+
+/// Compare without exiting early, so timing doesn't reveal how much of a key matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Log filter, either a level like "debug" or per-module directives like
+    /// "info,minmodmon_agent=debug".
+    pub level: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ModelConfig {
     pub architecture: String,
+    /// Who made the model, shown to clients.
+    #[serde(default = "default_owned_by")]
+    pub owned_by: String,
+    pub description: Option<String>,
     pub download_link: String,
     pub vocab: String,
     pub role_system: RoleConfig,
     pub role_user: RoleConfig,
     pub role_assistant: RoleConfig,
     pub stop_sequence: Vec<u16>,
+    /// Tokens that can never be generated, by default only the end-of-text token.
+    #[serde(default = "default_banned_tokens")]
+    pub banned_tokens: Vec<u16>,
+    /// Layer of the state to use as embedding, by default the mean of all layers is used.
+    pub embedding_layer: Option<usize>,
+}
+
+fn default_owned_by() -> String {
+    "unknown".to_string()
+}
+
+fn default_banned_tokens() -> Vec<u16> {
+    vec![0]
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -45,3 +379,114 @@ pub struct RoleConfig {
     pub prefix: Vec<u16>,
     pub suffix: Vec<u16>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base_path_is_normalized() {
+        assert_eq!(normalize_base_path("").unwrap(), "");
+        assert_eq!(normalize_base_path("/").unwrap(), "");
+        assert_eq!(normalize_base_path("/minmodmon").unwrap(), "/minmodmon");
+        assert_eq!(normalize_base_path("/minmodmon//").unwrap(), "/minmodmon");
+        assert_eq!(normalize_base_path("/a/b/").unwrap(), "/a/b");
+    }
+
+    #[test]
+    fn base_path_needs_leading_slash() {
+        assert!(normalize_base_path("minmodmon").is_err());
+        assert!(normalize_base_path("minmodmon/").is_err());
+    }
+
+    /// A config that passes validation, with paths that exist wherever the tests run.
+    fn valid_config() -> Config {
+        let mut config = Config::default();
+        config.paths.data_dir = std::env::temp_dir();
+        config.paths.models_dir = std::env::temp_dir();
+        config
+    }
+
+    fn key(name: &str, key: &str, scope: KeyScope) -> ApiKeyConfig {
+        ApiKeyConfig {
+            name: name.to_string(),
+            key: key.to_string(),
+            scope,
+        }
+    }
+
+    #[test]
+    fn validate_accepts_defaults_and_normalizes() {
+        let mut config = valid_config();
+        config.server.base_path = "/minmodmon/".to_string();
+        config.auth.keys = vec![
+            key("alice", "a", KeyScope::Inference),
+            key("bob", "b", KeyScope::Admin),
+        ];
+
+        config.validate().unwrap();
+        assert_eq!(config.server.base_path, "/minmodmon");
+    }
+
+    #[test]
+    fn validate_rejects_zero_limits() {
+        let mut config = valid_config();
+        config.models.slots = 0;
+        assert!(config.validate().is_err());
+
+        let mut config = valid_config();
+        config.queue.max_pending = 0;
+        assert!(config.validate().is_err());
+
+        let mut config = valid_config();
+        config.limits.max_choices = 0;
+        assert!(config.validate().is_err());
+
+        let mut config = valid_config();
+        config.defaults.max_tokens = config.limits.max_tokens + 1;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn validate_rejects_invalid_server() {
+        let mut config = valid_config();
+        config.server.addresses.clear();
+        assert!(config.validate().is_err());
+
+        let mut config = valid_config();
+        config.server.unix_socket_mode = Some(0o1777);
+        assert!(config.validate().is_err());
+
+        let mut config = valid_config();
+        config.server.base_path = "minmodmon".to_string();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn validate_rejects_missing_directories() {
+        let mut config = valid_config();
+        config.paths.models_dir = std::env::temp_dir().join("minmodmon-does-not-exist");
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn validate_rejects_ambiguous_keys() {
+        let mut config = valid_config();
+        config.auth.keys = vec![key("alice", "", KeyScope::Inference)];
+        assert!(config.validate().is_err());
+
+        let mut config = valid_config();
+        config.auth.keys = vec![
+            key("alice", "a", KeyScope::Inference),
+            key("alice", "b", KeyScope::Inference),
+        ];
+        assert!(config.validate().is_err());
+
+        let mut config = valid_config();
+        config.auth.keys = vec![
+            key("alice", "a", KeyScope::Inference),
+            key("bob", "a", KeyScope::Admin),
+        ];
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,39 @@
+use thiserror::Error;
+
+/// Errors caused by how the agent was used, rather than by something going wrong internally.
+///
+/// These are returned wrapped in `anyhow::Error`, callers can downcast to find them.
+#[derive(Error, Debug)]
+pub enum AgentError {
+    #[error("no model is currently loaded")]
+    NoActiveModel,
+    #[error("model {0:?} does not exist")]
+    ModelNotFound(String),
+    #[error("model {0:?} is not available, its weights file is missing")]
+    ModelNotAvailable(String),
+    #[error("model {0:?} is not loaded")]
+    ModelNotLoaded(String),
+    #[error("another model is already being loaded")]
+    LoadInProgress,
+    #[error("timed out waiting for model {0:?} to load")]
+    ModelLoadTimeout(String),
+    #[error("failed to load model {id:?}: {message}")]
+    ModelLoadFailed { id: String, message: String },
+    #[error("request was cancelled")]
+    Cancelled,
+    #[error("{message}")]
+    InvalidInput {
+        message: String,
+        /// The request parameter that was invalid, if known.
+        param: Option<String>,
+    },
+}
+
+impl AgentError {
+    pub fn invalid_input(message: impl Into<String>, param: impl Into<String>) -> Self {
+        Self::InvalidInput {
+            message: message.into(),
+            param: Some(param.into()),
+        }
+    }
+}
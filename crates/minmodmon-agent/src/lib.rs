@@ -1,11 +1,17 @@
 mod active_model;
+mod batch;
 pub mod config;
+mod error;
 mod sampler;
 mod service;
 pub mod types;
 
 pub use self::{
-    active_model::ActiveModel,
+    active_model::{ActiveModel, GenerateSettings, Generation, ModelSlot, StopReason},
+    error::AgentError,
     sampler::SamplerSettings,
-    service::{agent_service, start_activate_model, ActiveModelRef, AgentService},
+    service::{
+        agent_service, request_model, start_activate_model, ActiveModelRef, AgentService,
+        LoadError, LoadJob, LoadJobStatus,
+    },
 };
@@ -1,24 +1,48 @@
 use std::collections::HashMap;
 
 use anyhow::Error;
+use fastrand::Rng;
 use web_rwkv::tensor::{TensorCpu, TensorInit, TensorShape};
 
+#[derive(Clone)]
 pub struct SamplerSettings {
     pub temperature: f32,
     pub presence_penalty: f32,
     pub frequency_penalty: f32,
+    /// Bias added to the logits of specific tokens.
+    pub logit_bias: HashMap<u16, f32>,
+    /// Seed for sampling, the same seed will always pick the same tokens for the same logits.
+    pub seed: Option<u64>,
+}
+
+/// Seed of the choice at `index`, when generating multiple choices for one request.
+pub fn choice_seed(seed: Option<u64>, index: usize) -> Option<u64> {
+    seed.map(|seed| seed.wrapping_add(index as u64))
 }
 
 /// TODO: Refactor into a cleaner separate `SamplerSettings`/`FrequencyState`.
-#[derive(Default)]
 pub struct Sampler {
     occurrences: HashMap<u16, u32>,
+    rng: Rng,
 }
 
 impl Sampler {
+    pub fn new(settings: &SamplerSettings) -> Self {
+        let rng = match settings.seed {
+            Some(seed) => Rng::with_seed(seed),
+            None => Rng::new(),
+        };
+
+        Self {
+            occurrences: HashMap::new(),
+            rng,
+        }
+    }
+
     pub fn apply_penalties(
         &self,
         settings: &SamplerSettings,
+        banned_tokens: &[u16],
         logits: &TensorCpu<f32>,
     ) -> Result<TensorCpu<f32>, Error> {
         // Convert to f32 to work with it
@@ -26,21 +50,34 @@ impl Sampler {
         let mut logits: Vec<_> = logits.iter().cloned().collect();
 
         // Apply repetition penalties
-        logits[0] = f32::NEG_INFINITY;
         for (&token, &count) in &self.occurrences {
             let penalty = settings.presence_penalty + count as f32 * settings.frequency_penalty;
             logits[token as usize] -= penalty;
         }
+
+        // Apply requested biases, ignoring tokens that aren't in the vocab
+        for (&token, &bias) in &settings.logit_bias {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                *logit += bias;
+            }
+        }
+
+        // Banned tokens can never be picked
+        for &token in banned_tokens {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
         let logits = TensorCpu::from_data(shape, logits)?;
 
         Ok(logits)
     }
 
-    pub fn sample(&self, settings: &SamplerSettings, logits: &[f32]) -> u16 {
+    pub fn sample(&mut self, settings: &SamplerSettings, logits: &[f32]) -> u16 {
         let max = logits.iter().fold(f32::NEG_INFINITY, |acc, x| acc.max(*x));
         let min = logits.iter().fold(f32::INFINITY, |acc, x| acc.min(*x));
 
-        let mut dart = fastrand::f32();
+        let mut dart = self.rng.f32();
         let power = 1.0 - f32::powf(dart, settings.temperature * f32::powf(dart, 10.0));
         dart = f32::powf(dart, power);
         dart = min + dart * (max - min);
@@ -68,3 +105,91 @@ impl Sampler {
         self.occurrences.insert(token, count);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(seed: Option<u64>) -> SamplerSettings {
+        SamplerSettings {
+            temperature: 1.0,
+            presence_penalty: 0.0,
+            frequency_penalty: 0.0,
+            logit_bias: HashMap::new(),
+            seed,
+        }
+    }
+
+    /// Sample a sequence of tokens, all from the same probabilities.
+    fn sample_sequence(settings: &SamplerSettings) -> Vec<u16> {
+        // Mostly close to the most likely token, so sampling doesn't nearly always pick that one
+        let probabilities: Vec<f32> = (0..64)
+            .map(|index| match index {
+                0 => 0.0,
+                _ => 0.999 + index as f32 * 0.000_01,
+            })
+            .collect();
+        let mut sampler = Sampler::new(settings);
+
+        (0..32)
+            .map(|_| sampler.sample(settings, &probabilities))
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_picks_same_tokens() {
+        let settings = settings(Some(42));
+        let tokens = sample_sequence(&settings);
+        assert!(tokens.iter().any(|&token| token != tokens[0]));
+        assert_eq!(tokens, sample_sequence(&settings));
+    }
+
+    #[test]
+    fn choices_get_different_seeds() {
+        assert_eq!(choice_seed(None, 1), None);
+        assert_eq!(choice_seed(Some(42), 0), Some(42));
+        assert_eq!(choice_seed(Some(u64::MAX), 1), Some(0));
+
+        let first = sample_sequence(&settings(choice_seed(Some(42), 0)));
+        let second = sample_sequence(&settings(choice_seed(Some(42), 1)));
+        assert_ne!(first, second);
+    }
+
+    fn penalized(settings: &SamplerSettings, sampler: &Sampler, banned: &[u16]) -> Vec<f32> {
+        let logits = TensorCpu::from_data([4, 1, 1, 1], vec![1.0; 4]).unwrap();
+        let logits = sampler.apply_penalties(settings, banned, &logits).unwrap();
+        logits.iter().cloned().collect()
+    }
+
+    #[test]
+    fn logit_bias_is_added_ignoring_unknown_tokens() {
+        let mut settings = settings(None);
+        settings.logit_bias = HashMap::from([(1, 2.0), (2, -0.5), (100, 5.0)]);
+        let sampler = Sampler::new(&settings);
+
+        assert_eq!(penalized(&settings, &sampler, &[]), [1.0, 3.0, 0.5, 1.0]);
+    }
+
+    #[test]
+    fn banned_tokens_override_bias() {
+        let mut settings = settings(None);
+        settings.logit_bias = HashMap::from([(2, 100.0)]);
+        let sampler = Sampler::new(&settings);
+
+        let logits = penalized(&settings, &sampler, &[0, 2, 100]);
+        assert_eq!(logits, [f32::NEG_INFINITY, 1.0, f32::NEG_INFINITY, 1.0]);
+    }
+
+    #[test]
+    fn repetition_penalties_combine_with_bias() {
+        let mut settings = settings(None);
+        settings.presence_penalty = 0.5;
+        settings.frequency_penalty = 0.25;
+        settings.logit_bias = HashMap::from([(3, 1.0)]);
+        let mut sampler = Sampler::new(&settings);
+        sampler.consume_token(3);
+        sampler.consume_token(3);
+
+        assert_eq!(penalized(&settings, &sampler, &[]), [1.0, 1.0, 1.0, 1.0]);
+    }
+}
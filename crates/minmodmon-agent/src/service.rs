@@ -1,22 +1,29 @@
 use std::{
-    collections::HashMap,
-    path::Path,
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
     },
+    time::{Duration, Instant},
 };
 
-use anyhow::{bail, Context as _, Error};
+use anyhow::{Context as _, Error};
 use salvo::Depot;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify};
+use tokio_util::sync::CancellationToken;
 use tracing::{event, Level};
 
 use crate::{
-    active_model::ActiveModel,
-    config::{load_model_configs, ModelConfig},
+    active_model::{read_weights_metadata, ActiveModel, WeightsMetadata},
+    config::{load_model_configs, Config, ModelConfig, Quantization},
+    error::AgentError,
+    types::ModelInfo,
 };
 
+/// How many load jobs are kept around to be polled, older ones are forgotten.
+const MAX_LOAD_JOBS: usize = 16;
+
 pub fn agent_service(depot: &Depot) -> Result<Arc<AgentService>, Error> {
     depot
         .obtain::<Arc<AgentService>>()
@@ -26,64 +33,157 @@ pub fn agent_service(depot: &Depot) -> Result<Arc<AgentService>, Error> {
 }
 
 pub struct AgentService {
+    config: Config,
     known_models: HashMap<String, KnownModelInfo>,
-    active_model: Mutex<Option<ActiveModelRef>>,
+    active_model: Mutex<Option<LoadedModel>>,
     loading: AtomicBool,
+    load_error: Mutex<Option<LoadError>>,
+    load_finished: Notify,
+    next_job: AtomicU64,
+    /// Most recent load jobs, oldest first.
+    load_jobs: Mutex<VecDeque<LoadJob>>,
+    started: Instant,
+}
+
+struct LoadedModel {
+    id: String,
+    model: ActiveModelRef,
+}
+
+/// Error of the last model load, if it failed.
+#[derive(Debug, Clone)]
+pub struct LoadError {
+    pub id: String,
+    pub message: String,
+}
+
+/// A model load started through `start_activate_model`, kept around so it can be polled.
+#[derive(Debug, Clone)]
+pub struct LoadJob {
+    pub id: String,
+    pub model: String,
+    pub quantization: Quantization,
+    pub status: LoadJobStatus,
+}
+
+#[derive(Debug, Clone)]
+pub enum LoadJobStatus {
+    Loading,
+    Succeeded,
+    Failed { message: String },
 }
 
 pub struct KnownModelInfo {
     config: ModelConfig,
-    available: bool,
+    weights_path: PathBuf,
+    /// Details of the weights file, if it exists.
+    weights: Option<WeightsMetadata>,
 }
 
-pub type ActiveModelRef = Arc<Mutex<ActiveModel>>;
+pub type ActiveModelRef = Arc<ActiveModel>;
 
 impl AgentService {
-    pub async fn create() -> Result<Self, Error> {
+    pub async fn create(config: Config) -> Result<Self, Error> {
         event!(Level::INFO, "creating agent service");
 
-        let model_configs = load_model_configs().context("failed to load model configs")?;
+        let models_dir = &config.paths.models_dir;
+        let model_configs =
+            load_model_configs(models_dir).context("failed to load model configs")?;
         let known_models = model_configs
             .into_iter()
             .map(|(id, config)| {
                 // Check if the safetensors file for this model exists
-                let weights_path = format!("data/{}.st", id);
-                let weights_path = Path::new(&weights_path);
-                let available = weights_path.exists();
+                let weights_path = models_dir.join(format!("{}.st", id));
+                let weights = weights_path
+                    .exists()
+                    .then(|| read_weights_metadata(&weights_path))
+                    .transpose()?;
 
-                let info = KnownModelInfo { config, available };
+                let info = KnownModelInfo {
+                    config,
+                    weights_path,
+                    weights,
+                };
 
-                (id, info)
+                Ok((id, info))
             })
-            .collect();
+            .collect::<Result<_, Error>>()?;
 
         let value = AgentService {
+            config,
             known_models,
             active_model: Mutex::new(None),
             loading: AtomicBool::new(false),
+            load_error: Mutex::new(None),
+            load_finished: Notify::new(),
+            next_job: AtomicU64::new(0),
+            load_jobs: Mutex::new(VecDeque::new()),
+            started: Instant::now(),
         };
 
         Ok(value)
     }
 
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
     pub fn known_models(&self) -> &HashMap<String, KnownModelInfo> {
         &self.known_models
     }
 
     pub async fn active_model(&self) -> Option<ActiveModelRef> {
         let value = self.active_model.lock().await;
-        value.clone()
+        value.as_ref().map(|loaded| loaded.model.clone())
     }
 
+    /// Get the ID of the active model, without waiting for the model itself to be free.
     pub async fn active_model_id(&self) -> Option<String> {
-        let active_model = self.active_model().await?;
-        let active_model = active_model.lock().await;
-        Some(active_model.info().id)
+        let value = self.active_model.lock().await;
+        value.as_ref().map(|loaded| loaded.id.clone())
     }
 
     pub fn loading(&self) -> bool {
         self.loading.load(Ordering::SeqCst)
     }
+
+    pub async fn load_error(&self) -> Option<LoadError> {
+        let value = self.load_error.lock().await;
+        value.clone()
+    }
+
+    /// Get the ID of the model currently being loaded, if any.
+    pub async fn loading_model_id(&self) -> Option<String> {
+        let jobs = self.load_jobs.lock().await;
+        jobs.iter()
+            .find(|job| matches!(job.status, LoadJobStatus::Loading))
+            .map(|job| job.model.clone())
+    }
+
+    pub async fn load_job(&self, id: &str) -> Option<LoadJob> {
+        let jobs = self.load_jobs.lock().await;
+        jobs.iter().find(|job| job.id == id).cloned()
+    }
+
+    /// Unload the active model, requests still using it finish first.
+    pub async fn unload_model(&self, id: &str) -> Result<(), Error> {
+        let mut slot = self.active_model.lock().await;
+
+        match slot.as_ref() {
+            Some(loaded) if loaded.id == id => {}
+            _ => return Err(AgentError::ModelNotLoaded(id.to_string()).into()),
+        }
+
+        event!(Level::INFO, id, "unloading model");
+        *slot = None;
+
+        Ok(())
+    }
+
+    /// How long ago the service was created.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
 }
 
 impl KnownModelInfo {
@@ -92,50 +192,211 @@ impl KnownModelInfo {
     }
 
     pub fn available(&self) -> bool {
-        self.available
+        self.weights.is_some()
+    }
+
+    /// Get metadata information of the model, while it isn't loaded.
+    pub fn info(&self, id: &str) -> ModelInfo {
+        ModelInfo {
+            id: id.to_string(),
+            object: "model".to_string(),
+            created: self.weights.map_or(0, |weights| weights.created),
+            owned_by: self.config.owned_by.clone(),
+            description: self.config.description.clone(),
+            loaded: false,
+            metadata: None,
+        }
     }
 }
 
 // TODO: Figure out how to better architect shared managers/services for functions like this.
+/// Start loading a model in the background, returning the job tracking it.
+///
+/// Only one model can be loading at a time.
 pub async fn start_activate_model(
     service: Arc<AgentService>,
     id: String,
-    quant_nf8: bool,
-) -> Result<(), Error> {
+    quantization: Quantization,
+) -> Result<LoadJob, Error> {
     event!(Level::INFO, "activating model {:?}", id);
 
     let model_info = service
         .known_models
         .get(&id)
-        .context("failed to find model in config")?;
+        .ok_or_else(|| AgentError::ModelNotFound(id.clone()))?;
 
-    if !model_info.available {
-        bail!("model not available")
+    if !model_info.available() {
+        return Err(AgentError::ModelNotAvailable(id).into());
     }
 
     let config = model_info.config.clone();
-    let future = async move {
-        service.loading.store(true, Ordering::SeqCst);
+    let weights_path = model_info.weights_path.clone();
+
+    // Set this before starting, so anyone checking right after sees we're loading
+    if service.loading.swap(true, Ordering::SeqCst) {
+        return Err(AgentError::LoadInProgress.into());
+    }
+
+    let index = service.next_job.fetch_add(1, Ordering::SeqCst);
+    let job_id = format!("load-{}", index);
+    let job = LoadJob {
+        id: job_id.clone(),
+        model: id.clone(),
+        quantization,
+        status: LoadJobStatus::Loading,
+    };
 
-        let result = activate_model_task(service.clone(), id, config, quant_nf8).await;
+    // Only one model loads at a time, so the jobs being dropped here are always finished
+    let mut jobs = service.load_jobs.lock().await;
+    jobs.push_back(job.clone());
+    while jobs.len() > MAX_LOAD_JOBS {
+        jobs.pop_front();
+    }
+    drop(jobs);
+
+    let future = async move {
+        let result = activate_model_task(
+            service.clone(),
+            id.clone(),
+            config,
+            weights_path,
+            quantization,
+        )
+        .await;
 
-        if let Err(error) = result {
+        let load_error = result.err().map(|error| {
             // TODO: Do something with this in the dashboard
             event!(Level::ERROR, "error while activating model:\n{:?}", error);
+            LoadError {
+                id,
+                message: format!("{:#}", error),
+            }
+        });
+        let status = match &load_error {
+            Some(error) => LoadJobStatus::Failed {
+                message: error.message.clone(),
+            },
+            None => LoadJobStatus::Succeeded,
+        };
+        let mut jobs = service.load_jobs.lock().await;
+        if let Some(job) = jobs.iter_mut().find(|job| job.id == job_id) {
+            job.status = status;
         }
+        drop(jobs);
+        *service.load_error.lock().await = load_error;
 
         service.loading.store(false, Ordering::SeqCst);
+        service.load_finished.notify_waiters();
     };
     tokio::task::spawn(future);
 
-    Ok(())
+    Ok(job)
+}
+
+/// Get the model requested by a client, which has to be the active model.
+///
+/// If enabled in the config and the client is allowed to load models, a requested model that
+/// isn't loaded gets loaded, waiting until it's done. If a model is already loading, that's
+/// waited for first. An empty ID requests whichever model is active.
+///
+/// Waiting stops once `cancel` is cancelled, a load that was started keeps going.
+pub async fn request_model(
+    service: &Arc<AgentService>,
+    id: &str,
+    can_load: bool,
+    cancel: &CancellationToken,
+) -> Result<ActiveModelRef, Error> {
+    let config = &service.config.models;
+
+    // Check if this is a model that could be loaded at all
+    if !id.is_empty() {
+        let model_info = service
+            .known_models
+            .get(id)
+            .ok_or_else(|| AgentError::ModelNotFound(id.to_string()))?;
+
+        if !model_info.available() {
+            return Err(AgentError::ModelNotAvailable(id.to_string()).into());
+        }
+    }
+
+    let wait = async {
+        let mut started = false;
+
+        loop {
+            // Register for being notified before checking, so we can't miss a notification
+            let notified = service.load_finished.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if !service.loading() {
+                {
+                    let slot = service.active_model.lock().await;
+                    match slot.as_ref() {
+                        Some(loaded) if id.is_empty() || loaded.id == id => {
+                            return Ok(loaded.model.clone());
+                        }
+                        None if id.is_empty() => return Err(AgentError::NoActiveModel),
+                        _ => {}
+                    }
+                }
+
+                // If we already tried loading it, report why that didn't work
+                if started {
+                    let load_error = service.load_error().await;
+                    let message = load_error
+                        .filter(|error| error.id == id)
+                        .map(|error| error.message)
+                        .unwrap_or_else(|| "replaced by another model".to_string());
+                    let id = id.to_string();
+                    return Err(AgentError::ModelLoadFailed { id, message });
+                }
+
+                // Loading replaces the model everyone else is using, so not everyone can do it
+                if !config.auto_load || !can_load {
+                    return Err(AgentError::ModelNotLoaded(id.to_string()));
+                }
+
+                event!(Level::INFO, id, "automatically loading requested model");
+                let quantization = service.config.defaults.quantization;
+                match start_activate_model(service.clone(), id.to_string(), quantization).await {
+                    Ok(_) => started = true,
+                    // Someone else just started loading, wait for that before trying again
+                    Err(error)
+                        if matches!(
+                            error.downcast_ref::<AgentError>(),
+                            Some(AgentError::LoadInProgress)
+                        ) => {}
+                    Err(error) => {
+                        return Err(AgentError::ModelLoadFailed {
+                            id: id.to_string(),
+                            message: format!("{:#}", error),
+                        });
+                    }
+                }
+            }
+
+            notified.await;
+        }
+    };
+
+    let timeout = Duration::from_secs(config.auto_load_timeout);
+    let result = tokio::select! {
+        result = tokio::time::timeout(timeout, wait) => {
+            result.map_err(|_| AgentError::ModelLoadTimeout(id.to_string()))?
+        }
+        _ = cancel.cancelled() => return Err(AgentError::Cancelled.into()),
+    };
+
+    Ok(result?)
 }
 
 async fn activate_model_task(
     service: Arc<AgentService>,
     id: String,
     config: ModelConfig,
-    quant_nf8: bool,
+    weights_path: PathBuf,
+    quantization: Quantization,
 ) -> Result<(), Error> {
     // Unload any existing model, if there is one
     {
@@ -144,13 +405,18 @@ async fn activate_model_task(
     }
 
     // Load the new model
-    let active_model = ActiveModel::create(id, config, quant_nf8).await?;
-    let active_model = Arc::new(Mutex::new(active_model));
+    let num_slots = service.config.models.slots;
+    let active_model =
+        ActiveModel::create(id.clone(), config, &weights_path, quantization, num_slots).await?;
+    let active_model = Arc::new(active_model);
 
     // Store the new model
     {
         let mut slot = service.active_model.lock().await;
-        *slot = Some(active_model);
+        *slot = Some(LoadedModel {
+            id,
+            model: active_model,
+        });
     }
 
     Ok(())
@@ -2,8 +2,12 @@
 //!
 //! Matches completion API standards used by many platforms.
 
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
+use crate::config::{ModelConfig, Quantization};
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ModelList {
     pub object: String,
@@ -17,6 +21,25 @@ pub struct ModelInfo {
     pub object: String,
     pub created: u64,
     pub owned_by: String,
+    /// Extension, what the model is for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Extension, if the model is currently loaded rather than only installed.
+    pub loaded: bool,
+    /// Extension, details only known once the model is loaded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<ModelMetadata>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModelMetadata {
+    pub architecture: String,
+    pub num_layers: usize,
+    pub embedding_size: usize,
+    pub vocab_size: usize,
+    pub quantization: Quantization,
+    /// Size of the weights file in bytes.
+    pub file_size: u64,
 }
 
 #[derive(Serialize, Deserialize, Hash, Debug, Clone)]
@@ -33,6 +56,34 @@ pub struct ChatRequest {
     pub temperature: Option<f32>,
     pub presence_penalty: Option<f32>,
     pub frequency_penalty: Option<f32>,
+    /// Bias added to the logits of tokens, from -100 to 100.
+    pub logit_bias: Option<HashMap<u16, f32>>,
+    pub stop: Option<StopStrings>,
+    pub stream: Option<bool>,
+    /// Amount of alternative choices to generate.
+    pub n: Option<usize>,
+    /// Seed for sampling, for reproducible output.
+    pub seed: Option<u64>,
+    pub logprobs: Option<bool>,
+    /// Amount of most likely alternatives to report for every token, requires `logprobs`.
+    pub top_logprobs: Option<usize>,
+}
+
+/// Client stop strings, either a single string or a list of them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum StopStrings {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl StopStrings {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            StopStrings::Single(value) => vec![value],
+            StopStrings::Multiple(values) => values,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -41,6 +92,7 @@ pub struct ChatResponse {
     pub object: String,
     pub created: u64,
     pub model: String,
+    pub system_fingerprint: String,
     pub choices: Vec<ChatResponseChoice>,
     pub usage: UsageReport,
 }
@@ -49,14 +101,306 @@ pub struct ChatResponse {
 pub struct ChatResponseChoice {
     pub index: usize,
     pub message: ChatMessage,
+    pub logprobs: Option<ChatLogprobs>,
+
+    /// Should only be "stop" or "length" in minmodmon.
+    pub finish_reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatLogprobs {
+    pub content: Vec<TokenLogprob>,
+}
+
+/// Log probability information of a generated token.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f32,
+    pub bytes: Vec<u8>,
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f32,
+    pub bytes: Vec<u8>,
+}
+
+/// Partial chat response, sent as a server-sent event when streaming.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatChunk {
+    pub id: String,
+    pub object: String,
+    pub created: u64,
+    pub model: String,
+    pub system_fingerprint: String,
+    pub choices: Vec<ChatChunkChoice>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatChunkChoice {
+    pub index: usize,
+    pub delta: ChatDelta,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ChatDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompletionRequest {
+    pub model: String,
+    pub prompt: CompletionPrompt,
+    pub max_tokens: Option<usize>,
+    pub temperature: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    /// Bias added to the logits of tokens, from -100 to 100.
+    pub logit_bias: Option<HashMap<u16, f32>>,
+    pub stop: Option<StopStrings>,
+    pub seed: Option<u64>,
+}
+
+/// Raw prompt, either as text or as already tokenized input.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum CompletionPrompt {
+    Text(String),
+    Tokens(Vec<u16>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompletionResponse {
+    pub id: String,
+    pub object: String,
+    pub created: u64,
+    pub model: String,
+    pub system_fingerprint: String,
+    pub choices: Vec<CompletionChoice>,
+    pub usage: UsageReport,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompletionChoice {
+    pub index: usize,
+    pub text: String,
 
     /// Should only be "stop" or "length" in minmodmon.
     pub finish_reason: String,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmbeddingRequest {
+    pub model: String,
+    pub input: EmbeddingInput,
+}
+
+/// Input to embed, either one or multiple texts or already tokenized inputs.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    Text(String),
+    Texts(Vec<String>),
+    Tokens(Vec<u16>),
+    TokenLists(Vec<Vec<u16>>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmbeddingList {
+    pub object: String,
+    pub data: Vec<Embedding>,
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Embedding {
+    pub object: String,
+    pub embedding: Vec<f32>,
+    pub index: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmbeddingUsage {
+    pub prompt_tokens: usize,
+    pub total_tokens: usize,
+}
+
+/// Text or chat messages to tokenize, exactly one of the two should be given.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenizeRequest {
+    pub input: Option<String>,
+    /// Messages are assembled with the prompt format of the model, as they would be for a chat
+    /// completion.
+    pub messages: Option<Vec<ChatMessage>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenizeResponse {
+    pub model: String,
+    pub tokens: Vec<u16>,
+    pub pieces: Vec<TokenPiece>,
+}
+
+/// The text and raw bytes a single token represents.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenPiece {
+    pub token: String,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DetokenizeRequest {
+    pub tokens: Vec<u16>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DetokenizeResponse {
+    pub model: String,
+    pub text: String,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UsageReport {
     pub prompt_tokens: usize,
     pub completion_tokens: usize,
     pub total_tokens: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_tokens_details: Option<PromptTokensDetails>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PromptTokensDetails {
+    /// Prompt tokens that were restored from cache, rather than processed.
+    pub cached_tokens: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CancelResponse {
+    pub id: String,
+    pub object: String,
+}
+
+/// Liveness or readiness of the server.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HealthResponse {
+    pub status: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatusResponse {
+    /// If a model is currently being loaded.
+    pub loading: bool,
+    pub model: Option<String>,
+    pub quantization: Option<Quantization>,
+    /// Error of the last model load, if it failed.
+    pub load_error: Option<StatusLoadError>,
+    pub uptime_secs: u64,
+    pub queue: StatusQueue,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatusLoadError {
+    pub model: String,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatusQueue {
+    pub running: usize,
+    pub pending: usize,
+    pub max_pending: usize,
+    pub slots: usize,
+}
+
+/// Requests waiting for or using the model.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueueStatus {
+    pub max_pending: usize,
+    pub slots: usize,
+    pub running: Vec<QueuedRequest>,
+    /// Waiting requests, in the order they will run.
+    pub pending: Vec<QueuedRequest>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueuedRequest {
+    /// Hidden for requests made with other API keys, unless the caller is an admin.
+    pub id: Option<String>,
+    pub kind: String,
+    /// Milliseconds since the request entered its current stage.
+    pub elapsed_ms: u64,
+}
+
+/// Every model the server knows about, for managing them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AdminModelList {
+    pub object: String,
+    pub data: Vec<AdminModelInfo>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AdminModelInfo {
+    pub id: String,
+    pub object: String,
+    /// If the weights file exists, so the model can be loaded.
+    pub available: bool,
+    pub status: AdminModelStatus,
+    pub config: ModelConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AdminModelStatus {
+    Unloaded,
+    Loading,
+    Loaded,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LoadModelRequest {
+    /// Quantization to load with, by default the configured one.
+    pub quantization: Option<Quantization>,
+}
+
+/// Progress of loading a model in the background.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LoadJobInfo {
+    pub id: String,
+    pub object: String,
+    pub model: String,
+    pub quantization: Quantization,
+    pub status: LoadJobState,
+    /// Why loading failed, if it did.
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LoadJobState {
+    Loading,
+    Succeeded,
+    Failed,
+}
+
+/// Error body, returned with a matching HTTP status code.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorResponse {
+    pub error: ErrorInfo,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorInfo {
+    pub message: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub code: Option<String>,
+    pub param: Option<String>,
 }
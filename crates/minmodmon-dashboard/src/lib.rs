@@ -1,6 +1,12 @@
+use std::net::SocketAddr;
+
 use anyhow::{Context as _, Error};
 use salvo::{
     handler,
+    http::{
+        cookie::{Cookie, SameSite},
+        StatusCode,
+    },
     writing::{Redirect, Text},
     Depot, Request, Response, Router,
 };
@@ -8,11 +14,20 @@ use serde::Serialize;
 use tinytemplate::TinyTemplate;
 use tracing::{event, Level};
 
-use minmodmon_agent::{agent_service, start_activate_model};
+use minmodmon_agent::{
+    agent_service,
+    config::{AuthConfig, Config, KeyScope, Quantization},
+    start_activate_model,
+};
+
+/// Cookie the dashboard keeps the admin key in after logging in.
+const KEY_COOKIE: &str = "minmodmon-key";
 
 pub fn create_router() -> Result<Router, Error> {
     let router = Router::new()
         .get(handle)
+        .push(Router::with_path("login").post(handle_login))
+        .push(Router::with_path("logout").post(handle_logout))
         .push(Router::with_path("load_model").post(handle_load_model))
         .push(Router::with_path("splash-bg.png").get(handle_splash));
 
@@ -20,14 +35,19 @@ pub fn create_router() -> Result<Router, Error> {
 }
 
 #[handler]
-async fn handle(depot: &mut Depot, res: &mut Response) -> Result<(), Error> {
+async fn handle(req: &mut Request, depot: &mut Depot, res: &mut Response) -> Result<(), Error> {
     let service = agent_service(depot)?;
 
+    let config = service.config();
+    if !is_admin(req, &config.auth) {
+        return render_login(res, config, false);
+    }
+
     let loading = service.loading();
-    let active_model_id = service.active_model_id().await;
+    let active_model = service.active_model().await.map(|model| model.info());
 
     // Prepare template
-    let template = std::fs::read_to_string("./data/dashboard.html")?;
+    let template = std::fs::read_to_string(config.paths.data_dir.join("dashboard.html"))?;
     let mut tt = TinyTemplate::new();
     tt.add_template("dashboard", &template)?;
 
@@ -37,16 +57,51 @@ async fn handle(depot: &mut Depot, res: &mut Response) -> Result<(), Error> {
         let context = ModelContext {
             id: id.clone(),
             available: info.available(),
+            owned_by: info.config().owned_by.clone(),
+            description: info.config().description.clone().unwrap_or_default(),
             download_link: info.config().download_link.clone(),
         };
         models.push(context);
     }
     models.sort_by(|a, b| a.id.cmp(&b.id));
 
+    // Show the URL the API is reachable at, as the browser sees the server
+    let server = &config.server;
+    let api_url = match (&server.public_url, req.header::<String>("host")) {
+        (Some(public_url), _) => format!("{}/api", public_url.trim_end_matches('/')),
+        (None, Some(host)) => format!("http://{}{}/api", host, server.base_path),
+        (None, None) => match server.addresses.first() {
+            Some(address) => {
+                let address = SocketAddr::new(*address, server.port);
+                format!("http://{}{}/api", address, server.base_path)
+            }
+            None => format!("http://localhost{}/api", server.base_path),
+        },
+    };
+
     // Prepare context data
     let context = Context {
-        loaded_model: active_model_id.unwrap_or_else(|| "None".to_string()),
+        base_path: server.base_path.clone(),
+        api_url,
+        loaded_model: active_model
+            .as_ref()
+            .map_or_else(|| "None".to_string(), |info| info.id.clone()),
+        loaded_metadata: active_model.and_then(|info| info.metadata).map(|metadata| {
+            LoadedModelContext {
+                architecture: metadata.architecture,
+                num_layers: metadata.num_layers,
+                embedding_size: metadata.embedding_size,
+                vocab_size: metadata.vocab_size,
+                quantization: match metadata.quantization {
+                    Quantization::Int8 => "Int8",
+                    Quantization::Nf4 => "NF4",
+                }
+                .to_string(),
+                file_size: format!("{:.1} GB", metadata.file_size as f64 / 1e9),
+            }
+        }),
         loading,
+        auth_enabled: config.auth.enabled(),
         models,
     };
 
@@ -59,15 +114,31 @@ async fn handle(depot: &mut Depot, res: &mut Response) -> Result<(), Error> {
 
 #[derive(Serialize)]
 struct Context {
+    base_path: String,
+    api_url: String,
     loaded_model: String,
+    loaded_metadata: Option<LoadedModelContext>,
     loading: bool,
+    auth_enabled: bool,
     models: Vec<ModelContext>,
 }
 
+#[derive(Serialize)]
+struct LoadedModelContext {
+    architecture: String,
+    num_layers: usize,
+    embedding_size: usize,
+    vocab_size: usize,
+    quantization: String,
+    file_size: String,
+}
+
 #[derive(Serialize)]
 struct ModelContext {
     id: String,
     available: bool,
+    owned_by: String,
+    description: String,
     download_link: String,
 }
 
@@ -81,6 +152,12 @@ async fn handle_load_model(
 
     let service = agent_service(depot)?;
 
+    let config = service.config();
+    if !is_admin(req, &config.auth) {
+        res.render(Redirect::other(format!("{}/", config.server.base_path)));
+        return Ok(());
+    }
+
     let model_id = req
         .form::<String>("model-id")
         .await
@@ -90,18 +167,116 @@ async fn handle_load_model(
         .await
         .context("failed to get model-quantization")?;
 
-    let quant_nf8 = model_quantization == "nf8";
-    start_activate_model(service.clone(), model_id, quant_nf8)
+    let quantization = Quantization::from_name(&model_quantization)
+        .with_context(|| format!("unknown quantization {:?}", model_quantization))?;
+    start_activate_model(service.clone(), model_id, quantization)
         .await
         .context("failed to start model activation")?;
 
-    res.render(Redirect::other("/"));
+    res.render(Redirect::other(format!("{}/", config.server.base_path)));
+
+    Ok(())
+}
+
+#[handler]
+async fn handle_login(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), Error> {
+    let service = agent_service(depot)?;
+    let config = service.config();
+
+    let key = req
+        .form::<String>("key")
+        .await
+        .context("failed to get key")?;
+
+    // Only admin keys can use the dashboard, as it can load models
+    let found = config.auth.find_key(&key);
+    let Some(found) = found.filter(|found| found.scope >= KeyScope::Admin) else {
+        event!(Level::WARN, "dashboard login failed");
+        return render_login(res, config, true);
+    };
+    event!(Level::INFO, name = found.name, "dashboard login");
+
+    let base_path = &config.server.base_path;
+    let cookie = Cookie::build((KEY_COOKIE, key))
+        .path(cookie_path(base_path))
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .build();
+    res.add_cookie(cookie);
+    res.render(Redirect::other(format!("{}/", base_path)));
+
+    Ok(())
+}
+
+#[handler]
+async fn handle_logout(depot: &mut Depot, res: &mut Response) -> Result<(), Error> {
+    let service = agent_service(depot)?;
+    let base_path = &service.config().server.base_path;
+
+    let mut cookie = Cookie::build((KEY_COOKIE, ""))
+        .path(cookie_path(base_path))
+        .build();
+    cookie.make_removal();
+    res.add_cookie(cookie);
+    res.render(Redirect::other(format!("{}/", base_path)));
 
     Ok(())
 }
 
+/// Check if the dashboard was logged into with an admin key, always true if auth is disabled.
+fn is_admin(req: &Request, auth: &AuthConfig) -> bool {
+    if !auth.enabled() {
+        return true;
+    }
+
+    req.cookie(KEY_COOKIE)
+        .and_then(|cookie| auth.find_key(cookie.value()))
+        .is_some_and(|found| found.scope >= KeyScope::Admin)
+}
+
+/// Scope the cookie to the dashboard, "/minmodmon" also matches "/minmodmon/" per cookie rules.
+fn cookie_path(base_path: &str) -> String {
+    match base_path {
+        "" => "/".to_string(),
+        value => value.to_string(),
+    }
+}
+
+fn render_login(res: &mut Response, config: &Config, failed: bool) -> Result<(), Error> {
+    let template = std::fs::read_to_string(config.paths.data_dir.join("login.html"))?;
+    let mut tt = TinyTemplate::new();
+    tt.add_template("login", &template)?;
+
+    let context = LoginContext {
+        base_path: config.server.base_path.clone(),
+        failed,
+    };
+    let rendered = tt.render("login", &context)?;
+    res.status_code(StatusCode::UNAUTHORIZED);
+    res.render(Text::Html(rendered));
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct LoginContext {
+    base_path: String,
+    failed: bool,
+}
+
 #[handler]
-async fn handle_splash(req: &mut Request, res: &mut Response) -> Result<(), Error> {
-    res.send_file("./data/splash-bg.png", req.headers()).await;
+async fn handle_splash(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), Error> {
+    let service = agent_service(depot)?;
+
+    let path = service.config().paths.data_dir.join("splash-bg.png");
+    res.send_file(path, req.headers()).await;
     Ok(())
 }
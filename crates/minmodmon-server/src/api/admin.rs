@@ -0,0 +1,150 @@
+use salvo::{handler, http::StatusCode, writing::Json, Depot, Request, Response};
+use tracing::{event, Level};
+
+use minmodmon_agent::{
+    agent_service, start_activate_model,
+    types::{
+        AdminModelInfo, AdminModelList, AdminModelStatus, LoadJobInfo, LoadJobState,
+        LoadModelRequest,
+    },
+    AgentError, LoadJob, LoadJobStatus,
+};
+
+use crate::{api::error::ApiError, cache::cache_service};
+
+#[handler]
+pub async fn handle_clear_cache(depot: &mut Depot, res: &mut Response) -> Result<(), ApiError> {
+    let cache = cache_service(depot)?;
+
+    cache.clear().await;
+    event!(Level::INFO, "cleared state cache");
+
+    res.status_code(StatusCode::NO_CONTENT);
+
+    Ok(())
+}
+
+#[handler]
+pub async fn handle_models(depot: &mut Depot, res: &mut Response) -> Result<(), ApiError> {
+    let service = agent_service(depot)?;
+
+    let active_model_id = service.active_model_id().await;
+    let loading_model_id = service.loading_model_id().await;
+
+    let mut data: Vec<_> = service
+        .known_models()
+        .iter()
+        .map(|(id, info)| {
+            let status = if Some(id) == active_model_id.as_ref() {
+                AdminModelStatus::Loaded
+            } else if Some(id) == loading_model_id.as_ref() {
+                AdminModelStatus::Loading
+            } else {
+                AdminModelStatus::Unloaded
+            };
+
+            AdminModelInfo {
+                id: id.clone(),
+                object: "model".to_string(),
+                available: info.available(),
+                status,
+                config: info.config().clone(),
+            }
+        })
+        .collect();
+    data.sort_by(|a, b| a.id.cmp(&b.id));
+
+    res.render(Json(AdminModelList {
+        object: "list".to_string(),
+        data,
+    }));
+
+    Ok(())
+}
+
+/// Start loading a model, responding with a job that can be polled for progress.
+#[handler]
+pub async fn handle_load_model(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), ApiError> {
+    let service = agent_service(depot)?;
+
+    let id = req.param::<String>("id").unwrap_or_default();
+
+    // All options are optional, so an empty body is fine
+    let request = if req.payload().await?.is_empty() {
+        LoadModelRequest::default()
+    } else {
+        req.parse_json::<LoadModelRequest>().await?
+    };
+    let quantization = request
+        .quantization
+        .unwrap_or(service.config().defaults.quantization);
+
+    let job = start_activate_model(service.clone(), id, quantization).await?;
+
+    res.status_code(StatusCode::ACCEPTED);
+    res.render(Json(load_job_info(job)));
+
+    Ok(())
+}
+
+#[handler]
+pub async fn handle_unload_model(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), ApiError> {
+    let service = agent_service(depot)?;
+
+    let id = req.param::<String>("id").unwrap_or_default();
+    if !service.known_models().contains_key(&id) {
+        return Err(AgentError::ModelNotFound(id).into());
+    }
+    service.unload_model(&id).await?;
+
+    res.status_code(StatusCode::NO_CONTENT);
+
+    Ok(())
+}
+
+#[handler]
+pub async fn handle_load_job(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), ApiError> {
+    let service = agent_service(depot)?;
+
+    let id = req.param::<String>("id").unwrap_or_default();
+    let job = service
+        .load_job(&id)
+        .await
+        .ok_or_else(|| ApiError::NotFound {
+            message: format!("load job {:?} does not exist", id),
+            code: "job_not_found",
+        })?;
+
+    res.render(Json(load_job_info(job)));
+
+    Ok(())
+}
+
+fn load_job_info(job: LoadJob) -> LoadJobInfo {
+    let (status, error) = match job.status {
+        LoadJobStatus::Loading => (LoadJobState::Loading, None),
+        LoadJobStatus::Succeeded => (LoadJobState::Succeeded, None),
+        LoadJobStatus::Failed { message } => (LoadJobState::Failed, Some(message)),
+    };
+
+    LoadJobInfo {
+        id: job.id,
+        object: "load_job".to_string(),
+        model: job.model,
+        quantization: job.quantization,
+        status,
+        error,
+    }
+}
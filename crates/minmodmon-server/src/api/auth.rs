@@ -0,0 +1,126 @@
+use anyhow::{Context, Error};
+use salvo::{handler, Depot, FlowCtrl, Request, Response, Scribe};
+use tracing::{event, Level};
+
+use minmodmon_agent::{agent_service, config::KeyScope};
+
+use crate::api::error::ApiError;
+
+/// Who made a request, stored in the depot once the request is authorized.
+#[derive(Debug, Clone)]
+pub struct Caller {
+    /// Name of the API key used, none if auth is disabled.
+    pub key: Option<String>,
+    pub scope: KeyScope,
+}
+
+impl Caller {
+    pub fn is_admin(&self) -> bool {
+        self.scope >= KeyScope::Admin
+    }
+
+    /// Key whose requests the caller is limited to seeing and cancelling, none for admins.
+    pub fn restricted_to(&self) -> Option<&str> {
+        if self.is_admin() {
+            return None;
+        }
+
+        self.key.as_deref()
+    }
+}
+
+pub fn caller(depot: &Depot) -> Result<Caller, Error> {
+    depot
+        .obtain::<Caller>()
+        .ok()
+        .cloned()
+        .context("failed to get caller")
+}
+
+/// Require an API key with at least inference access, if auth is enabled.
+#[handler]
+pub async fn require_inference(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+    ctrl: &mut FlowCtrl,
+) {
+    match authorize(req, depot, KeyScope::Inference) {
+        Ok(caller) => {
+            depot.inject(caller);
+        }
+        Err(error) => {
+            error.render(res);
+            ctrl.skip_rest();
+        }
+    }
+}
+
+/// Require an API key with admin access, if auth is enabled.
+#[handler]
+pub async fn require_admin(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+    ctrl: &mut FlowCtrl,
+) {
+    match authorize(req, depot, KeyScope::Admin) {
+        Ok(caller) => {
+            depot.inject(caller);
+        }
+        Err(error) => {
+            error.render(res);
+            ctrl.skip_rest();
+        }
+    }
+}
+
+/// Check the "Authorization: Bearer" key of a request has the scope needed.
+///
+/// Without auth enabled, anyone has full access.
+fn authorize(req: &Request, depot: &Depot, scope: KeyScope) -> Result<Caller, ApiError> {
+    let service = agent_service(depot)?;
+    let auth = &service.config().auth;
+
+    if !auth.enabled() {
+        let caller = Caller {
+            key: None,
+            scope: KeyScope::Admin,
+        };
+        return Ok(caller);
+    }
+
+    let key = req
+        .header::<String>("authorization")
+        .and_then(|value| value.strip_prefix("Bearer ").map(str::to_string))
+        .ok_or_else(|| ApiError::Unauthorized {
+            message: "missing API key, expected an \"Authorization: Bearer\" header".to_string(),
+        })?;
+
+    let found = auth
+        .find_key(key.trim())
+        .ok_or_else(|| ApiError::Unauthorized {
+            message: "invalid API key".to_string(),
+        })?;
+
+    if found.scope < scope {
+        event!(
+            Level::WARN,
+            name = found.name,
+            "API key lacks required scope"
+        );
+        let scope = match scope {
+            KeyScope::Inference => "inference",
+            KeyScope::Admin => "admin",
+        };
+        return Err(ApiError::Forbidden {
+            message: format!("API key doesn't have {} access", scope),
+        });
+    }
+
+    let caller = Caller {
+        key: Some(found.name.clone()),
+        scope: found.scope,
+    };
+    Ok(caller)
+}
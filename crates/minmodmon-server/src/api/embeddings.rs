@@ -0,0 +1,82 @@
+use std::time::{Instant, SystemTime};
+
+use salvo::{handler, writing::Json, Depot, Request, Response};
+
+use minmodmon_agent::{
+    agent_service, request_model,
+    types::{Embedding, EmbeddingInput, EmbeddingList, EmbeddingRequest, EmbeddingUsage},
+};
+
+use crate::{
+    api::{auth::caller, error::ApiError},
+    metrics::metrics_service,
+    queue::queue_service,
+    requests::request_service,
+};
+
+#[handler]
+pub async fn handle_embeddings(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), ApiError> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs();
+
+    let service = agent_service(depot)?;
+    let caller = caller(depot)?;
+    let requests = request_service(depot)?;
+    let queue = queue_service(depot)?;
+    let metrics = metrics_service(depot)?;
+
+    // Parse the input
+    let request = req.parse_json::<EmbeddingRequest>().await?;
+
+    // Get the requested model, waiting for our turn
+    // Cancelling the request stops it at any point from here on
+    let handle = requests.register(now, caller.key.clone());
+    let cancel = handle.token();
+    let active_model = request_model(&service, &request.model, caller.is_admin(), &cancel).await?;
+    let active_model = queue
+        .acquire(handle.id(), "embeddings", active_model, &cancel)
+        .await?;
+
+    // Tokenize the input if necessary
+    let inputs = match request.input {
+        EmbeddingInput::Text(text) => vec![active_model.tokenize(&text)?],
+        EmbeddingInput::Texts(texts) => texts
+            .iter()
+            .map(|text| active_model.tokenize(text))
+            .collect::<Result<_, _>>()?,
+        EmbeddingInput::Tokens(tokens) => vec![tokens],
+        EmbeddingInput::TokenLists(lists) => lists,
+    };
+
+    // Embed every input separately
+    let started = Instant::now();
+    let prompt_tokens = inputs.iter().map(Vec::len).sum();
+    let mut data = Vec::with_capacity(inputs.len());
+    for (index, tokens) in inputs.into_iter().enumerate() {
+        let embedding = active_model.embed(tokens, &cancel).await?;
+        data.push(Embedding {
+            object: "embedding".to_string(),
+            embedding,
+            index,
+        });
+    }
+    metrics.record_prefill(prompt_tokens, started.elapsed());
+
+    let list = EmbeddingList {
+        object: "list".to_string(),
+        data,
+        model: active_model.info().id,
+        usage: EmbeddingUsage {
+            prompt_tokens,
+            total_tokens: prompt_tokens,
+        },
+    };
+    res.render(Json(list));
+
+    Ok(())
+}
@@ -0,0 +1,223 @@
+use anyhow::Error;
+use salvo::{
+    http::{
+        header::{RETRY_AFTER, WWW_AUTHENTICATE},
+        ParseError, StatusCode,
+    },
+    writing::Json,
+    Response, Scribe,
+};
+use tracing::{event, Level};
+
+use minmodmon_agent::{
+    types::{ErrorInfo, ErrorResponse},
+    AgentError,
+};
+
+use crate::queue::QueueFull;
+
+/// Error returned by API handlers, rendered in the OpenAI error format.
+#[derive(Debug)]
+pub enum ApiError {
+    InvalidRequest {
+        message: String,
+        param: Option<String>,
+    },
+    Unauthorized {
+        message: String,
+    },
+    Forbidden {
+        message: String,
+    },
+    NotFound {
+        message: String,
+        code: &'static str,
+    },
+    Conflict {
+        message: String,
+        code: &'static str,
+    },
+    Unavailable {
+        message: String,
+        code: &'static str,
+    },
+    Overloaded {
+        message: String,
+        retry_after: u64,
+    },
+    Internal(Error),
+}
+
+impl ApiError {
+    pub fn invalid_request(message: impl Into<String>, param: impl Into<String>) -> Self {
+        Self::InvalidRequest {
+            message: message.into(),
+            param: Some(param.into()),
+        }
+    }
+}
+
+/// Errors the client caused are found by downcasting, anything else is an internal error.
+impl<E> From<E> for ApiError
+where
+    E: Into<Error>,
+{
+    fn from(error: E) -> Self {
+        let error = error.into();
+
+        if let Some(error) = error.downcast_ref::<AgentError>() {
+            let message = error.to_string();
+            return match error {
+                AgentError::NoActiveModel => Self::Unavailable {
+                    message,
+                    code: "no_model_loaded",
+                },
+                AgentError::ModelNotFound(_) => Self::NotFound {
+                    message,
+                    code: "model_not_found",
+                },
+                AgentError::ModelNotAvailable(_) => Self::Conflict {
+                    message,
+                    code: "model_not_available",
+                },
+                AgentError::ModelNotLoaded(_) => Self::Conflict {
+                    message,
+                    code: "model_not_loaded",
+                },
+                AgentError::LoadInProgress => Self::Conflict {
+                    message,
+                    code: "model_loading",
+                },
+                AgentError::ModelLoadTimeout(_) => Self::Unavailable {
+                    message,
+                    code: "model_load_timeout",
+                },
+                AgentError::ModelLoadFailed { .. } => Self::Unavailable {
+                    message,
+                    code: "model_load_failed",
+                },
+                AgentError::Cancelled => Self::Conflict {
+                    message,
+                    code: "request_cancelled",
+                },
+                AgentError::InvalidInput { param, .. } => Self::InvalidRequest {
+                    message,
+                    param: param.clone(),
+                },
+            };
+        }
+
+        if let Some(error) = error.downcast_ref::<QueueFull>() {
+            return Self::Overloaded {
+                message: error.to_string(),
+                retry_after: error.retry_after,
+            };
+        }
+
+        if let Some(error) = error.downcast_ref::<ParseError>() {
+            return Self::InvalidRequest {
+                message: format!("failed to parse request: {}", error),
+                param: None,
+            };
+        }
+
+        Self::Internal(error)
+    }
+}
+
+impl ApiError {
+    /// Get the status code and body of the error, as sent to the client.
+    pub fn into_response(self) -> (StatusCode, ErrorResponse) {
+        let (status, kind, code, message, param) = match self {
+            ApiError::InvalidRequest { message, param } => (
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                None,
+                message,
+                param,
+            ),
+            ApiError::Unauthorized { message } => (
+                StatusCode::UNAUTHORIZED,
+                "invalid_request_error",
+                Some("invalid_api_key"),
+                message,
+                None,
+            ),
+            ApiError::Forbidden { message } => (
+                StatusCode::FORBIDDEN,
+                "invalid_request_error",
+                Some("insufficient_scope"),
+                message,
+                None,
+            ),
+            ApiError::NotFound { message, code } => (
+                StatusCode::NOT_FOUND,
+                "invalid_request_error",
+                Some(code),
+                message,
+                None,
+            ),
+            ApiError::Conflict { message, code } => (
+                StatusCode::CONFLICT,
+                "invalid_request_error",
+                Some(code),
+                message,
+                None,
+            ),
+            ApiError::Unavailable { message, code } => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "server_error",
+                Some(code),
+                message,
+                None,
+            ),
+            ApiError::Overloaded { message, .. } => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "server_error",
+                Some("queue_full"),
+                message,
+                None,
+            ),
+            ApiError::Internal(error) => {
+                // Details can include paths and other internals, so they only go to the log
+                event!(Level::ERROR, "error while handling request:\n{:?}", error);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "server_error",
+                    None,
+                    "internal server error".to_string(),
+                    None,
+                )
+            }
+        };
+
+        let response = ErrorResponse {
+            error: ErrorInfo {
+                message,
+                kind: kind.to_string(),
+                code: code.map(str::to_string),
+                param,
+            },
+        };
+
+        (status, response)
+    }
+}
+
+impl Scribe for ApiError {
+    fn render(self, res: &mut Response) {
+        match &self {
+            ApiError::Unauthorized { .. } => {
+                let _ = res.add_header(WWW_AUTHENTICATE, "Bearer", true);
+            }
+            ApiError::Overloaded { retry_after, .. } => {
+                let _ = res.add_header(RETRY_AFTER, retry_after.to_string(), true);
+            }
+            _ => {}
+        }
+
+        let (status, response) = self.into_response();
+        res.status_code(status);
+        res.render(Json(response));
+    }
+}
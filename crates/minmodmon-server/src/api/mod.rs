@@ -1,39 +1,91 @@
-use std::time::SystemTime;
+mod admin;
+mod auth;
+mod embeddings;
+mod error;
+mod status;
+mod tokenize;
 
-use anyhow::{Context, Error};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Error;
 use minmodmon_agent::types::{
-    ChatMessage, ChatRequest, ChatResponse, ChatResponseChoice, ModelList, UsageReport,
+    CancelResponse, ChatChunk, ChatChunkChoice, ChatDelta, ChatLogprobs, ChatMessage, ChatRequest,
+    ChatResponse, ChatResponseChoice, CompletionChoice, CompletionPrompt, CompletionRequest,
+    CompletionResponse, ModelList, PromptTokensDetails, StopStrings, UsageReport,
 };
-use salvo::{handler, writing::Json, Depot, Request, Response, Router};
+use salvo::{
+    handler,
+    sse::{self, SseEvent},
+    writing::Json,
+    Depot, Request, Response, Router,
+};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
 use tracing::{event, Level};
 
-use minmodmon_agent::{agent_service, SamplerSettings};
+use minmodmon_agent::{
+    agent_service, config::Config, request_model, GenerateSettings, SamplerSettings, StopReason,
+};
 
-use crate::cache::cache_service;
+use crate::{
+    api::{auth::caller, error::ApiError},
+    cache::cache_service,
+    metrics::{metrics_service, MetricsService},
+    queue::{queue_service, ModelPermit},
+    requests::{request_service, RequestHandle},
+};
 
 pub fn create_router() -> Result<Router, Error> {
+    let admin_router = Router::with_path("admin")
+        .hoop(auth::require_admin)
+        .push(Router::with_path("cache").delete(admin::handle_clear_cache))
+        .push(Router::with_path("models").get(admin::handle_models))
+        .push(Router::with_path("models/<id>/load").post(admin::handle_load_model))
+        .push(Router::with_path("models/<id>/unload").post(admin::handle_unload_model))
+        .push(Router::with_path("jobs/<id>").get(admin::handle_load_job));
+
     let router = Router::with_path("api")
+        .hoop(auth::require_inference)
         .push(Router::with_path("models").get(handle_models))
-        .push(Router::with_path("chat/completions").post(handle_chat_completions));
+        .push(Router::with_path("chat/completions").post(handle_chat_completions))
+        .push(Router::with_path("completions").post(handle_completions))
+        .push(Router::with_path("embeddings").post(embeddings::handle_embeddings))
+        .push(Router::with_path("status").get(status::handle_status))
+        .push(Router::with_path("queue").get(handle_queue))
+        .push(Router::with_path("requests/<id>/cancel").post(handle_cancel_request))
+        .push(Router::with_path("tokenize").post(tokenize::handle_tokenize))
+        .push(Router::with_path("detokenize").post(tokenize::handle_detokenize))
+        .push(admin_router);
 
     Ok(router)
 }
 
 #[handler]
-async fn handle_models(depot: &mut Depot, res: &mut Response) -> Result<(), Error> {
+async fn handle_models(depot: &mut Depot, res: &mut Response) -> Result<(), ApiError> {
     let service = agent_service(depot)?;
 
-    let mut list = ModelList {
-        object: "list".to_string(),
-        data: Vec::new(),
-    };
+    let active_model = service.active_model().await;
 
-    if let Some(active_model) = service.active_model().await {
-        let active_model = active_model.lock().await;
-        let info = active_model.info();
-        list.data.push(info);
-    }
+    // List every installed model, so clients can pick any of them to be loaded on request
+    let mut data: Vec<_> = service
+        .known_models()
+        .iter()
+        .filter(|(_, info)| info.available())
+        .map(|(id, info)| match &active_model {
+            Some(active_model) if active_model.info().id == *id => active_model.info(),
+            _ => info.info(id),
+        })
+        .collect();
+    data.sort_by(|a, b| a.id.cmp(&b.id));
 
+    let list = ModelList {
+        object: "list".to_string(),
+        data,
+    };
     res.render(Json(list));
 
     Ok(())
@@ -44,75 +96,253 @@ async fn handle_chat_completions(
     req: &mut Request,
     depot: &mut Depot,
     res: &mut Response,
-) -> Result<(), Error> {
+) -> Result<(), ApiError> {
+    let started = Instant::now();
     let now = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)?
         .as_secs();
 
     let service = agent_service(depot)?;
+    let caller = caller(depot)?;
     let cache = cache_service(depot)?;
-
-    // Get the current model
-    let active_model = service
-        .active_model()
-        .await
-        .context("failed to get active model")?;
-    let active_model = active_model.lock().await;
+    let requests = request_service(depot)?;
+    let queue = queue_service(depot)?;
+    let metrics = metrics_service(depot)?;
 
     // Parse the input
     let request = req.parse_json::<ChatRequest>().await?;
+    let config = service.config();
+    let n = request.n.unwrap_or(1);
+    if n == 0 || n > config.limits.max_choices {
+        let message = format!("n must be between 1 and {}", config.limits.max_choices);
+        return Err(ApiError::invalid_request(message, "n"));
+    }
+    let logprobs = request.logprobs.unwrap_or(false);
+    let top_logprobs = request.top_logprobs.unwrap_or(0);
+    if top_logprobs > 20 {
+        let message = "top_logprobs must be at most 20";
+        return Err(ApiError::invalid_request(message, "top_logprobs"));
+    }
+    if request.top_logprobs.is_some() && !logprobs {
+        let message = "top_logprobs can only be used when logprobs is true";
+        return Err(ApiError::invalid_request(message, "top_logprobs"));
+    }
+    // Streamed chunks don't carry logprobs, rather than silently leaving them out
+    if logprobs && request.stream.unwrap_or(false) {
+        let message = "logprobs are not supported when streaming";
+        return Err(ApiError::invalid_request(message, "logprobs"));
+    }
+
+    // Get the requested model, waiting for our turn
+    // Cancelling the request stops it at any point from here on
+    let handle = requests.register(now, caller.key.clone());
+    let cancel = handle.token();
+    let active_model = request_model(&service, &request.model, caller.is_admin(), &cancel).await?;
+    let active_model = queue
+        .acquire(handle.id(), "chat", active_model, &cancel)
+        .await?;
+    let model_id = active_model.info().id;
 
     // Check if we can restore from cache
     let mut skipped = 0;
-    if let Some((length, state)) = cache.query(&request.messages).await {
-        event!(Level::INFO, length, "restoring from cached state");
-        skipped = length;
-        active_model.import_state(state)?;
+    let mut cached_tokens = 0;
+    if let Some(cached) = cache.query(&model_id, &request.messages).await {
+        event!(
+            Level::INFO,
+            length = cached.length,
+            tokens = cached.tokens,
+            "restoring from cached state"
+        );
+        skipped = cached.length;
+        cached_tokens = cached.tokens;
+        metrics.record_cache_hit(skipped, cached_tokens);
+        active_model.import_state(cached.state).await?;
     } else {
         event!(Level::INFO, "could not restore from cached state, no match");
-        active_model.reset_state()?;
+        metrics.record_cache_miss();
+        active_model.reset_state().await?;
     }
 
     // Process remaining messages
+    let prefill_started = Instant::now();
+    let mut prompt_tokens = cached_tokens;
     for message in &request.messages[skipped..] {
-        active_model.process_message(message).await?;
+        prompt_tokens += active_model.process_message(message, &cancel).await?;
     }
+    let prefill_tokens = prompt_tokens - cached_tokens;
+    let prefill_time = prefill_started.elapsed();
 
     // Cache current state, after processing given non-cached messages
     let state = active_model.export_state().await?;
-    cache.set(&request.messages, state).await;
-
-    // Generate output
-    let max_tokens = request.max_tokens.unwrap_or(512);
-    let settings = SamplerSettings {
-        temperature: request.temperature.unwrap_or(0.8),
-        presence_penalty: request.presence_penalty.unwrap_or(0.3),
-        frequency_penalty: request.presence_penalty.unwrap_or(0.3),
+    cache
+        .set(&model_id, &request.messages, prompt_tokens, state)
+        .await;
+
+    // Generate output, until done or until the request is cancelled
+    let settings = GenerateSettings {
+        max_tokens: checked_max_tokens(request.max_tokens, config)?,
+        stop: request.stop.map(StopStrings::into_vec).unwrap_or_default(),
+        logprobs: logprobs.then_some(top_logprobs),
+        sampler: SamplerSettings {
+            temperature: request.temperature.unwrap_or(config.defaults.temperature),
+            presence_penalty: request
+                .presence_penalty
+                .unwrap_or(config.defaults.presence_penalty),
+            frequency_penalty: request
+                .frequency_penalty
+                .unwrap_or(config.defaults.frequency_penalty),
+            logit_bias: checked_logit_bias(request.logit_bias)?,
+            seed: request.seed,
+        },
+        cancel,
     };
-    let content = active_model.generate_message(max_tokens, &settings).await?;
 
-    // Serialize and send back the result
-    let message = ChatMessage {
-        role: "assistant".to_string(),
-        content,
+    if request.stream.unwrap_or(false) {
+        let stream = ChatStream {
+            active_model,
+            handle,
+            metrics,
+            started,
+            prefill_tokens,
+            prefill_time,
+            created: now,
+        };
+        stream_chat_completion(res, stream, n, settings);
+        return Ok(());
+    }
+
+    let mut first_token = Some(started);
+    let generations = active_model
+        .generate_messages(n, &settings, |_, _| {
+            if let Some(started) = first_token.take() {
+                metrics.record_first_token(started.elapsed());
+            }
+        })
+        .await?;
+    metrics.record_generations(prefill_tokens, prefill_time, &generations);
+
+    // Serialize and send back the result, the prompt is only processed once for all choices
+    let prompt_tokens = prompt_tokens + generations[0].prompt_tokens;
+    let completion_tokens = generations.iter().map(|g| g.completion_tokens).sum();
+    let choices = generations
+        .into_iter()
+        .enumerate()
+        .map(|(index, generation)| {
+            let message = ChatMessage {
+                role: "assistant".to_string(),
+                content: generation.content,
+            };
+            ChatResponseChoice {
+                index,
+                message,
+                logprobs: generation.logprobs.map(|content| ChatLogprobs { content }),
+                finish_reason: finish_reason(generation.stop_reason),
+            }
+        })
+        .collect();
+    let usage = UsageReport {
+        prompt_tokens,
+        completion_tokens,
+        total_tokens: prompt_tokens + completion_tokens,
+        prompt_tokens_details: Some(PromptTokensDetails { cached_tokens }),
     };
-    let choice = ChatResponseChoice {
+    let response = ChatResponse {
+        id: handle.id().to_string(),
+        object: "chat.completion".to_string(),
+        created: now,
+        model: active_model.info().id,
+        system_fingerprint: active_model.fingerprint(),
+        choices,
+        usage,
+    };
+    res.render(Json(response));
+
+    Ok(())
+}
+
+#[handler]
+async fn handle_completions(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), ApiError> {
+    let started = Instant::now();
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs();
+
+    let service = agent_service(depot)?;
+    let caller = caller(depot)?;
+    let requests = request_service(depot)?;
+    let queue = queue_service(depot)?;
+    let metrics = metrics_service(depot)?;
+    let config = service.config();
+
+    // Parse the input
+    let request = req.parse_json::<CompletionRequest>().await?;
+
+    // Get the requested model, waiting for our turn
+    // Cancelling the request stops it at any point from here on
+    let handle = requests.register(now, caller.key.clone());
+    let cancel = handle.token();
+    let active_model = request_model(&service, &request.model, caller.is_admin(), &cancel).await?;
+    let active_model = queue
+        .acquire(handle.id(), "completion", active_model, &cancel)
+        .await?;
+    let prompt = match request.prompt {
+        CompletionPrompt::Text(text) => active_model.tokenize(&text)?,
+        CompletionPrompt::Tokens(tokens) => tokens,
+    };
+
+    // Raw completions always start from a clean state
+    active_model.reset_state().await?;
+
+    // Generate output, until done or until the request is cancelled
+    let settings = GenerateSettings {
+        max_tokens: checked_max_tokens(request.max_tokens, config)?,
+        stop: request.stop.map(StopStrings::into_vec).unwrap_or_default(),
+        logprobs: None,
+        sampler: SamplerSettings {
+            temperature: request.temperature.unwrap_or(config.defaults.temperature),
+            presence_penalty: request
+                .presence_penalty
+                .unwrap_or(config.defaults.presence_penalty),
+            frequency_penalty: request
+                .frequency_penalty
+                .unwrap_or(config.defaults.frequency_penalty),
+            logit_bias: checked_logit_bias(request.logit_bias)?,
+            seed: request.seed,
+        },
+        cancel,
+    };
+    let mut first_token = Some(started);
+    let generation = active_model
+        .generate_completion(prompt, &settings, |_| {
+            if let Some(started) = first_token.take() {
+                metrics.record_first_token(started.elapsed());
+            }
+        })
+        .await?;
+    metrics.record_generations(0, Duration::ZERO, std::slice::from_ref(&generation));
+
+    // Serialize and send back the result
+    let choice = CompletionChoice {
         index: 0,
-        message,
-        // TODO: This needs to actually distinguish between if we stopped because we ran to the
-        //  token limit, or because the message is done.
-        finish_reason: "stop".to_string(),
+        text: generation.content,
+        finish_reason: finish_reason(generation.stop_reason),
     };
     let usage = UsageReport {
-        prompt_tokens: 0,
-        completion_tokens: 0,
-        total_tokens: 0,
+        prompt_tokens: generation.prompt_tokens,
+        completion_tokens: generation.completion_tokens,
+        total_tokens: generation.prompt_tokens + generation.completion_tokens,
+        prompt_tokens_details: None,
     };
-    let response = ChatResponse {
-        id: format!("req-{}", now),
-        object: "chat.completion".to_string(),
+    let response = CompletionResponse {
+        id: handle.id().to_string(),
+        object: "text_completion".to_string(),
         created: now,
         model: active_model.info().id,
+        system_fingerprint: active_model.fingerprint(),
         choices: vec![choice],
         usage,
     };
@@ -120,3 +350,202 @@ async fn handle_chat_completions(
 
     Ok(())
 }
+
+#[handler]
+async fn handle_queue(depot: &mut Depot, res: &mut Response) -> Result<(), ApiError> {
+    let service = agent_service(depot)?;
+    let caller = caller(depot)?;
+    let queue = queue_service(depot)?;
+    let requests = request_service(depot)?;
+
+    let slots = match service.active_model().await {
+        Some(active_model) => active_model.num_slots(),
+        None => 0,
+    };
+    let mut status = queue.status(slots);
+
+    // Other clients' request IDs would let this client cancel them
+    if let Some(key) = caller.restricted_to() {
+        for entry in status.running.iter_mut().chain(status.pending.iter_mut()) {
+            let owned = entry
+                .id
+                .as_ref()
+                .is_some_and(|id| requests.is_owned_by(id, key));
+            if !owned {
+                entry.id = None;
+            }
+        }
+    }
+
+    res.render(Json(status));
+
+    Ok(())
+}
+
+#[handler]
+async fn handle_cancel_request(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), ApiError> {
+    let caller = caller(depot)?;
+    let requests = request_service(depot)?;
+
+    // Requests made with other keys are reported as not found, to not reveal they exist
+    let id = req.param::<String>("id").unwrap_or_default();
+    if !requests.cancel(&id, caller.restricted_to()) {
+        return Err(ApiError::NotFound {
+            message: format!("request {:?} is not in progress", id),
+            code: "request_not_found",
+        });
+    }
+
+    event!(Level::INFO, id, "cancelled request");
+    res.render(Json(CancelResponse {
+        id,
+        object: "request.cancelled".to_string(),
+    }));
+
+    Ok(())
+}
+
+/// What a streamed chat completion keeps hold of until it's done.
+struct ChatStream {
+    active_model: ModelPermit,
+    handle: RequestHandle,
+    metrics: Arc<MetricsService>,
+    /// When the request was received, for measuring time to first token.
+    started: Instant,
+    /// Prompt processed by the handler, before generating.
+    prefill_tokens: usize,
+    prefill_time: Duration,
+    created: u64,
+}
+
+/// Generate the output in the background, sending each decoded piece of content as a
+/// server-sent event chunk.
+///
+/// If the client disconnects, sending fails and generation gets cancelled.
+fn stream_chat_completion(
+    res: &mut Response,
+    stream: ChatStream,
+    n: usize,
+    settings: GenerateSettings,
+) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+
+    let ChatStream {
+        active_model,
+        handle,
+        metrics,
+        started,
+        prefill_tokens,
+        prefill_time,
+        created,
+    } = stream;
+    let id = handle.id().to_string();
+    let model = active_model.info().id;
+    let system_fingerprint = active_model.fingerprint();
+    let chunk = move |index, delta, finish_reason| {
+        let chunk = ChatChunk {
+            id: id.clone(),
+            object: "chat.completion.chunk".to_string(),
+            created,
+            model: model.clone(),
+            system_fingerprint: system_fingerprint.clone(),
+            choices: vec![ChatChunkChoice {
+                index,
+                delta,
+                finish_reason,
+            }],
+        };
+        SseEvent::default().json(chunk)
+    };
+
+    let future = async move {
+        for index in 0..n {
+            let delta = ChatDelta {
+                role: Some("assistant".to_string()),
+                content: None,
+            };
+            let _ = sender.send(chunk(index, delta, None));
+        }
+
+        let mut first_token = Some(started);
+        let result = active_model
+            .generate_messages(n, &settings, |index, content| {
+                if let Some(started) = first_token.take() {
+                    metrics.record_first_token(started.elapsed());
+                }
+
+                let delta = ChatDelta {
+                    role: None,
+                    content: Some(content.to_string()),
+                };
+                if sender.send(chunk(index, delta, None)).is_err() {
+                    settings.cancel.cancel();
+                }
+            })
+            .await;
+
+        // Tell the client the reply is incomplete, rather than ending it like a finished one
+        let generations = match result {
+            Ok(value) => value,
+            Err(error) => {
+                let (_, response) = ApiError::from(error).into_response();
+                let _ = sender.send(SseEvent::default().json(response));
+                return;
+            }
+        };
+
+        metrics.record_generations(prefill_tokens, prefill_time, &generations);
+        for (index, generation) in generations.iter().enumerate() {
+            let finish_reason = finish_reason(generation.stop_reason);
+            let _ = sender.send(chunk(index, ChatDelta::default(), Some(finish_reason)));
+        }
+
+        // OpenAI-compatible clients expect a final marker after all chunks
+        let _ = sender.send(Ok(SseEvent::default().text("[DONE]")));
+
+        // Keep the request registered until we're done with it
+        drop(handle);
+    };
+    tokio::task::spawn(future);
+
+    let stream = UnboundedReceiverStream::new(receiver);
+    sse::stream(res, stream);
+}
+
+/// Map why generation stopped to an API finish reason.
+fn finish_reason(reason: StopReason) -> String {
+    let value = match reason {
+        StopReason::MaxTokens => "length",
+        StopReason::StopSequence | StopReason::StopString | StopReason::Cancelled => "stop",
+    };
+
+    value.to_string()
+}
+
+/// Get the maximum tokens to generate, within the configured limit.
+fn checked_max_tokens(value: Option<usize>, config: &Config) -> Result<usize, ApiError> {
+    let value = value.unwrap_or(config.defaults.max_tokens);
+
+    if value > config.limits.max_tokens {
+        let message = format!("max_tokens can be at most {}", config.limits.max_tokens);
+        return Err(ApiError::invalid_request(message, "max_tokens"));
+    }
+
+    Ok(value)
+}
+
+/// Validate the logit bias of a request is in the range supported by the API.
+fn checked_logit_bias(value: Option<HashMap<u16, f32>>) -> Result<HashMap<u16, f32>, ApiError> {
+    let value = value.unwrap_or_default();
+
+    if value.values().any(|bias| !(-100.0..=100.0).contains(bias)) {
+        let message = "logit_bias values must be between -100 and 100";
+        return Err(ApiError::invalid_request(message, "logit_bias"));
+    }
+
+    Ok(value)
+}
@@ -0,0 +1,40 @@
+use salvo::{handler, writing::Json, Depot, Response};
+
+use minmodmon_agent::{
+    agent_service,
+    types::{StatusLoadError, StatusQueue, StatusResponse},
+};
+
+use crate::{api::error::ApiError, queue::queue_service};
+
+#[handler]
+pub async fn handle_status(depot: &mut Depot, res: &mut Response) -> Result<(), ApiError> {
+    let service = agent_service(depot)?;
+    let queue = queue_service(depot)?;
+
+    let active_model = service.active_model().await;
+    let slots = active_model.as_ref().map_or(0, |model| model.num_slots());
+    let queue = queue.status(slots);
+
+    let load_error = service.load_error().await.map(|error| StatusLoadError {
+        model: error.id,
+        message: error.message,
+    });
+
+    let response = StatusResponse {
+        loading: service.loading(),
+        model: active_model.as_ref().map(|model| model.info().id),
+        quantization: active_model.as_ref().map(|model| model.quantization()),
+        load_error,
+        uptime_secs: service.uptime().as_secs(),
+        queue: StatusQueue {
+            running: queue.running.len(),
+            pending: queue.pending.len(),
+            max_pending: queue.max_pending,
+            slots: queue.slots,
+        },
+    };
+    res.render(Json(response));
+
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use anyhow::Error;
+use salvo::{handler, writing::Json, Depot, Request, Response};
+
+use minmodmon_agent::{
+    agent_service,
+    types::{DetokenizeRequest, DetokenizeResponse, TokenPiece, TokenizeRequest, TokenizeResponse},
+    AgentError,
+};
+
+use crate::api::error::ApiError;
+
+#[handler]
+pub async fn handle_tokenize(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), ApiError> {
+    let service = agent_service(depot)?;
+
+    // Get the current model
+    let active_model = service
+        .active_model()
+        .await
+        .ok_or(AgentError::NoActiveModel)?;
+
+    // Parse the input
+    let request = req.parse_json::<TokenizeRequest>().await?;
+    let tokens = match (request.input, request.messages) {
+        (Some(input), None) => active_model.tokenize(&input)?,
+        (None, Some(messages)) => active_model.assemble_prompt(&messages)?,
+        _ => {
+            let message = "exactly one of input or messages is required";
+            return Err(ApiError::invalid_request(message, "input"));
+        }
+    };
+
+    // Look up what every token represents
+    let pieces = tokens
+        .iter()
+        .map(|&token| {
+            let bytes = active_model.detokenize(&[token])?;
+            let piece = TokenPiece {
+                token: String::from_utf8_lossy(&bytes).to_string(),
+                bytes,
+            };
+            Ok(piece)
+        })
+        .collect::<Result<_, Error>>()?;
+
+    let response = TokenizeResponse {
+        model: active_model.info().id,
+        tokens,
+        pieces,
+    };
+    res.render(Json(response));
+
+    Ok(())
+}
+
+#[handler]
+pub async fn handle_detokenize(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), ApiError> {
+    let service = agent_service(depot)?;
+
+    // Get the current model
+    let active_model = service
+        .active_model()
+        .await
+        .ok_or(AgentError::NoActiveModel)?;
+
+    // Parse the input
+    let request = req.parse_json::<DetokenizeRequest>().await?;
+    let bytes = active_model.detokenize(&request.tokens)?;
+
+    let response = DetokenizeResponse {
+        model: active_model.info().id,
+        text: String::from_utf8_lossy(&bytes).to_string(),
+    };
+    res.render(Json(response));
+
+    Ok(())
+}
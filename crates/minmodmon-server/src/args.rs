@@ -0,0 +1,89 @@
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Error};
+use clap::Parser;
+
+use minmodmon_agent::config::{load_config, Config};
+
+/// Command line options, overriding the config file.
+///
+/// Every option can also be set through its environment variable, command line options take
+/// precedence over those.
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Args {
+    /// Config file to load, "./minmodmon.toml" is used if it exists.
+    #[arg(long, env = "MINMODMON_CONFIG")]
+    config: Option<PathBuf>,
+    /// Address to listen on, can be given multiple times.
+    #[arg(long = "address", env = "MINMODMON_ADDRESSES", value_delimiter = ',')]
+    addresses: Vec<IpAddr>,
+    /// Port to listen on.
+    #[arg(long, env = "MINMODMON_PORT")]
+    port: Option<u16>,
+    /// Prefix of all URLs, for example "/minmodmon" when running behind a reverse proxy.
+    #[arg(long, env = "MINMODMON_BASE_PATH")]
+    base_path: Option<String>,
+    /// Unix domain socket to listen on, alongside the addresses.
+    #[arg(long, env = "MINMODMON_UNIX_SOCKET")]
+    unix_socket: Option<PathBuf>,
+    /// Directory with dashboard templates and assets.
+    #[arg(long, env = "MINMODMON_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+    /// Directory with model configs and weights.
+    #[arg(long, env = "MINMODMON_MODELS_DIR")]
+    models_dir: Option<PathBuf>,
+    /// Model to load when the server starts.
+    #[arg(long, env = "MINMODMON_STARTUP_MODEL")]
+    startup_model: Option<String>,
+    /// Log filter, for example "debug" or "info,minmodmon_agent=debug".
+    #[arg(long, env = "MINMODMON_LOG")]
+    log: Option<String>,
+}
+
+impl Args {
+    /// Load the config file, apply overrides on top, and validate the result.
+    pub fn load_config(self) -> Result<Config, Error> {
+        let mut config = match &self.config {
+            Some(path) => {
+                if !path.exists() {
+                    bail!("config file {} doesn't exist", path.display());
+                }
+                load_config(path)?
+            }
+            None => load_config(Path::new("./minmodmon.toml"))?,
+        };
+
+        if !self.addresses.is_empty() {
+            config.server.addresses = self.addresses;
+        }
+        if let Some(port) = self.port {
+            config.server.port = port;
+        }
+        if let Some(base_path) = self.base_path {
+            config.server.base_path = base_path;
+        }
+        if let Some(unix_socket) = self.unix_socket {
+            config.server.unix_socket = Some(unix_socket);
+        }
+        if let Some(data_dir) = self.data_dir {
+            config.paths.data_dir = data_dir;
+        }
+        if let Some(models_dir) = self.models_dir {
+            config.paths.models_dir = models_dir;
+        }
+        if let Some(startup_model) = self.startup_model {
+            config.models.startup_model = Some(startup_model);
+        }
+        if let Some(log) = self.log {
+            config.logging.level = log;
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+}
@@ -24,10 +24,19 @@ pub struct CacheService {
 
 struct CacheEntry {
     length: usize,
+    tokens: usize,
     hash: u64,
     state: TensorCpu<f32>,
 }
 
+/// State restored from cache, covering the first `length` messages.
+pub struct CachedState {
+    pub length: usize,
+    /// Amount of prompt tokens processed into the state.
+    pub tokens: usize,
+    pub state: TensorCpu<f32>,
+}
+
 impl CacheService {
     pub fn create() -> Result<Self, Error> {
         let value = Self {
@@ -37,7 +46,7 @@ impl CacheService {
         Ok(value)
     }
 
-    pub async fn query(&self, messages: &[ChatMessage]) -> Option<(usize, TensorCpu<f32>)> {
+    pub async fn query(&self, model: &str, messages: &[ChatMessage]) -> Option<CachedState> {
         // Check if we have an entry at all
         let slot = self.entry.lock().await;
         let entry = slot.as_ref()?;
@@ -49,30 +58,49 @@ impl CacheService {
 
         // Hash the range of messages that might be in the cache
         let messages = &messages[0..entry.length];
-        let hash = hash_messages(messages);
+        let hash = hash_messages(model, messages);
 
         if entry.hash == hash {
-            return Some((entry.length, entry.state.clone()));
+            let value = CachedState {
+                length: entry.length,
+                tokens: entry.tokens,
+                state: entry.state.clone(),
+            };
+            return Some(value);
         }
 
         // Cached entry doesn't match
         None
     }
 
-    pub async fn set(&self, messages: &[ChatMessage], state: TensorCpu<f32>) {
-        let hash = hash_messages(messages);
+    pub async fn set(
+        &self,
+        model: &str,
+        messages: &[ChatMessage],
+        tokens: usize,
+        state: TensorCpu<f32>,
+    ) {
+        let hash = hash_messages(model, messages);
         let entry = CacheEntry {
             length: messages.len(),
+            tokens,
             hash,
             state,
         };
         let mut slot = self.entry.lock().await;
         *slot = Some(entry);
     }
+
+    pub async fn clear(&self) {
+        let mut slot = self.entry.lock().await;
+        *slot = None;
+    }
 }
 
-fn hash_messages(messages: &[ChatMessage]) -> u64 {
+/// Hash messages along with the model, as state can only be restored into the same model.
+fn hash_messages(model: &str, messages: &[ChatMessage]) -> u64 {
     let mut hasher = DefaultHasher::new();
+    model.hash(&mut hasher);
     for message in messages {
         message.hash(&mut hasher);
     }
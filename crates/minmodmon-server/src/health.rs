@@ -0,0 +1,47 @@
+//! Endpoints for process supervisors, these don't require an API key.
+
+use anyhow::Error;
+use salvo::{handler, http::StatusCode, writing::Json, Depot, Response, Router};
+
+use minmodmon_agent::{agent_service, types::HealthResponse};
+
+pub fn create_router() -> Result<Router, Error> {
+    let router = Router::new()
+        .push(Router::with_path("health").get(handle_health))
+        .push(Router::with_path("ready").get(handle_ready));
+
+    Ok(router)
+}
+
+/// The process is alive and serving requests.
+#[handler]
+async fn handle_health(res: &mut Response) {
+    let response = HealthResponse {
+        status: "ok".to_string(),
+    };
+    res.render(Json(response));
+}
+
+/// A model is loaded and can take requests.
+#[handler]
+async fn handle_ready(depot: &mut Depot, res: &mut Response) -> Result<(), Error> {
+    let service = agent_service(depot)?;
+
+    let status = if service.loading() {
+        "loading"
+    } else if service.active_model_id().await.is_none() {
+        "no_model"
+    } else {
+        "ready"
+    };
+
+    if status != "ready" {
+        res.status_code(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    let response = HealthResponse {
+        status: status.to_string(),
+    };
+    res.render(Json(response));
+
+    Ok(())
+}
@@ -1,42 +1,175 @@
 mod api;
+mod args;
 mod cache;
+mod health;
+mod metrics;
+mod queue;
+mod requests;
+#[cfg(unix)]
+mod unix_socket;
 
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use anyhow::{Context, Error};
+use clap::Parser;
 use salvo::{
-    affix::AffixList, conn::TcpListener, logging::Logger, Listener, Router, Server, Service,
+    affix::AffixList, conn::TcpListener, logging::Logger, server::ServerHandle, Listener, Router,
+    Server, Service,
 };
+use tokio::task::JoinSet;
+use tracing::{event, Level};
+use tracing_subscriber::EnvFilter;
 
-use minmodmon_agent::AgentService;
+use minmodmon_agent::{start_activate_model, AgentService};
 
-use crate::cache::CacheService;
+use crate::{
+    args::Args, cache::CacheService, metrics::MetricsService, queue::QueueService,
+    requests::RequestService,
+};
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    tracing_subscriber::fmt().init();
+    let config = Args::parse()
+        .load_config()
+        .context("invalid configuration")?;
+
+    let filter = EnvFilter::try_new(&config.logging.level).context("invalid logging.level")?;
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+
+    if !config.auth.enabled() {
+        event!(
+            Level::WARN,
+            "no API keys configured, anyone who can reach the server has full access"
+        );
+    }
+
+    let server_config = config.server.clone();
 
     // Create services
-    let model_service = AgentService::create()
+    let queue_service =
+        QueueService::create(config.queue.clone()).context("failed to create queue service")?;
+    let queue_service = Arc::new(queue_service);
+    let model_service = AgentService::create(config)
         .await
         .context("failed to create agent service")?;
+    let model_service = Arc::new(model_service);
     let cache_service = CacheService::create().context("failed to create cache service")?;
+    let cache_service = Arc::new(cache_service);
+    let request_service = RequestService::create().context("failed to create request service")?;
+    let request_service = Arc::new(request_service);
+    let metrics_service = MetricsService::create().context("failed to create metrics service")?;
+    let metrics_service = Arc::new(metrics_service);
+
+    if let Some(id) = &model_service.config().models.startup_model {
+        let quantization = model_service.config().defaults.quantization;
+        start_activate_model(model_service.clone(), id.clone(), quantization)
+            .await
+            .with_context(|| format!("failed to load startup model {:?}", id))?;
+    }
+
+    // Configure routes and services, every listener gets its own copy sharing the same services
+    let create_service = || -> Result<Service, Error> {
+        let dashboard_router = minmodmon_dashboard::create_router()?;
+        let health_router = health::create_router()?;
+        let metrics_router = metrics::create_router()?;
+        let api_router = api::create_router()?;
+        let router = match server_config.base_path.as_str() {
+            "" => Router::new(),
+            base_path => Router::with_path(base_path.trim_start_matches('/')),
+        };
+        let router = router
+            .hoop(metrics::track_requests)
+            .push(dashboard_router)
+            .push(health_router)
+            .push(metrics_router)
+            .push(api_router);
+
+        let affix = AffixList::new()
+            .inject(model_service.clone())
+            .inject(cache_service.clone())
+            .inject(request_service.clone())
+            .inject(queue_service.clone())
+            .inject(metrics_service.clone());
+        let service = Service::new(router).hoop(Logger::new()).hoop(affix);
+
+        Ok(service)
+    };
+
+    // Start a server for every address
+    let mut servers = JoinSet::new();
+    let mut handles = Vec::new();
+    for address in &server_config.addresses {
+        let address = SocketAddr::new(*address, server_config.port);
+        let acceptor = TcpListener::new(address)
+            .try_bind()
+            .await
+            .with_context(|| format!("failed to listen on {}", address))?;
+        event!(Level::INFO, %address, base_path = server_config.base_path, "listening");
+
+        let server = Server::new(acceptor);
+        handles.push(server.handle());
+        servers.spawn(server.try_serve(create_service()?));
+    }
+
+    // Also start one on the unix socket, which is removed again when we stop
+    #[cfg(unix)]
+    let _socket_guard = match &server_config.unix_socket {
+        Some(path) => {
+            let (acceptor, guard) = unix_socket::bind(path, server_config.unix_socket_mode).await?;
+            event!(Level::INFO, path = %path.display(), base_path = server_config.base_path, "listening");
+
+            let server = Server::new(acceptor);
+            handles.push(server.handle());
+            servers.spawn(server.try_serve(create_service()?));
+
+            Some(guard)
+        }
+        None => None,
+    };
+
+    tokio::task::spawn(stop_on_signal(handles));
+
+    // Run until all servers are stopped, or any of them fails
+    while let Some(result) = servers.join_next().await {
+        result?.context("server stopped")?;
+    }
+
+    event!(Level::INFO, "server stopped");
+
+    Ok(())
+}
+
+/// Stop all servers when asked to, giving running requests some time to finish.
+async fn stop_on_signal(handles: Vec<ServerHandle>) {
+    if let Err(error) = wait_for_signal().await {
+        event!(
+            Level::ERROR,
+            "failed to listen for shutdown signals: {}",
+            error
+        );
+        return;
+    }
+
+    event!(Level::INFO, "shutting down");
+    for handle in handles {
+        handle.stop_graceful(Duration::from_secs(30));
+    }
+}
+
+async fn wait_for_signal() -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
 
-    // Configure routes
-    let dashboard_router = minmodmon_dashboard::create_router()?;
-    let api_router = api::create_router()?;
-    let router = Router::new().push(dashboard_router).push(api_router);
-
-    // Configure the service
-    let affix = AffixList::new()
-        .inject(Arc::new(model_service))
-        .inject(Arc::new(cache_service));
-    let service = Service::new(router).hoop(Logger::new()).hoop(affix);
-
-    // Start the server
-    let acceptor = TcpListener::new("127.0.0.1:5000").bind().await;
-    let server = Server::new(acceptor);
-    server.serve(service).await;
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
 
     Ok(())
 }
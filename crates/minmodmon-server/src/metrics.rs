@@ -0,0 +1,244 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{Context, Error};
+use prometheus::{
+    exponential_buckets, Encoder, Gauge, Histogram, HistogramOpts, IntCounter, IntCounterVec,
+    IntGauge, Opts, Registry, TextEncoder,
+};
+use salvo::{handler, writing::Text, Depot, FlowCtrl, Request, Response, Router};
+
+use minmodmon_agent::{agent_service, Generation};
+
+use crate::queue::queue_service;
+
+pub fn metrics_service(depot: &Depot) -> Result<Arc<MetricsService>, Error> {
+    depot
+        .obtain::<Arc<MetricsService>>()
+        .ok()
+        .cloned()
+        .context("failed to get metrics service")
+}
+
+pub fn create_router() -> Result<Router, Error> {
+    let router = Router::with_path("metrics").get(handle_metrics);
+    Ok(router)
+}
+
+/// Aggregates what the server is doing, for scraping in the Prometheus text format.
+pub struct MetricsService {
+    registry: Registry,
+
+    requests: IntCounterVec,
+    prompt_tokens: IntCounter,
+    completion_tokens: IntCounter,
+    prefill_speed: Histogram,
+    decode_speed: Histogram,
+    time_to_first_token: Histogram,
+
+    cache_hits: IntCounter,
+    cache_misses: IntCounter,
+    cache_skipped_messages: IntCounter,
+    cache_skipped_tokens: IntCounter,
+
+    queue_running: IntGauge,
+    queue_pending: IntGauge,
+    model_load_duration: Gauge,
+}
+
+impl MetricsService {
+    pub fn create() -> Result<Self, Error> {
+        let registry = Registry::new_custom(Some("minmodmon".to_string()), None)?;
+
+        let speed_buckets = exponential_buckets(1.0, 2.0, 14)?;
+        let latency_buckets = exponential_buckets(0.01, 2.0, 14)?;
+
+        let value = Self {
+            requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "Handled HTTP requests"),
+                &["route", "status"],
+            )?,
+            prompt_tokens: IntCounter::new("prompt_tokens_total", "Processed prompt tokens")?,
+            completion_tokens: IntCounter::new(
+                "completion_tokens_total",
+                "Generated completion tokens",
+            )?,
+            prefill_speed: Histogram::with_opts(
+                HistogramOpts::new(
+                    "prefill_tokens_per_second",
+                    "Prompt processing speed per request",
+                )
+                .buckets(speed_buckets.clone()),
+            )?,
+            decode_speed: Histogram::with_opts(
+                HistogramOpts::new("decode_tokens_per_second", "Generation speed per request")
+                    .buckets(speed_buckets),
+            )?,
+            time_to_first_token: Histogram::with_opts(
+                HistogramOpts::new(
+                    "time_to_first_token_seconds",
+                    "Time from receiving a request until the first content was generated",
+                )
+                .buckets(latency_buckets),
+            )?,
+
+            cache_hits: IntCounter::new("cache_hits_total", "Chat requests restored from cache")?,
+            cache_misses: IntCounter::new(
+                "cache_misses_total",
+                "Chat requests that couldn't be restored from cache",
+            )?,
+            cache_skipped_messages: IntCounter::new(
+                "cache_skipped_messages_total",
+                "Messages that didn't have to be processed thanks to the cache",
+            )?,
+            cache_skipped_tokens: IntCounter::new(
+                "cache_skipped_tokens_total",
+                "Prompt tokens that didn't have to be processed thanks to the cache",
+            )?,
+
+            queue_running: IntGauge::new("queue_running", "Requests currently using a slot")?,
+            queue_pending: IntGauge::new("queue_pending", "Requests waiting for a slot")?,
+            model_load_duration: Gauge::new(
+                "model_load_duration_seconds",
+                "How long loading the active model took",
+            )?,
+
+            registry,
+        };
+
+        let registry = &value.registry;
+        registry.register(Box::new(value.requests.clone()))?;
+        registry.register(Box::new(value.prompt_tokens.clone()))?;
+        registry.register(Box::new(value.completion_tokens.clone()))?;
+        registry.register(Box::new(value.prefill_speed.clone()))?;
+        registry.register(Box::new(value.decode_speed.clone()))?;
+        registry.register(Box::new(value.time_to_first_token.clone()))?;
+        registry.register(Box::new(value.cache_hits.clone()))?;
+        registry.register(Box::new(value.cache_misses.clone()))?;
+        registry.register(Box::new(value.cache_skipped_messages.clone()))?;
+        registry.register(Box::new(value.cache_skipped_tokens.clone()))?;
+        registry.register(Box::new(value.queue_running.clone()))?;
+        registry.register(Box::new(value.queue_pending.clone()))?;
+        registry.register(Box::new(value.model_load_duration.clone()))?;
+
+        Ok(value)
+    }
+
+    /// Record prompt processing of a request that doesn't generate anything.
+    pub fn record_prefill(&self, tokens: usize, time: Duration) {
+        self.prompt_tokens.inc_by(tokens as u64);
+        observe_speed(&self.prefill_speed, tokens, time);
+    }
+
+    /// Record the generations of a request, along with the prompt the handler processed first.
+    ///
+    /// The prompt counts once for all generations, like in the usage report, so only the first
+    /// generation's own prompt tokens are added to it.
+    pub fn record_generations(
+        &self,
+        prefill_tokens: usize,
+        prefill_time: Duration,
+        generations: &[Generation],
+    ) {
+        let (tokens, time) = match generations.first() {
+            Some(first) => (
+                prefill_tokens + first.prompt_tokens,
+                prefill_time + first.prefill_time,
+            ),
+            None => (prefill_tokens, prefill_time),
+        };
+        self.record_prefill(tokens, time);
+
+        for generation in generations {
+            let tokens = generation.completion_tokens;
+            self.completion_tokens.inc_by(tokens as u64);
+            observe_speed(&self.decode_speed, tokens, generation.decode_time);
+        }
+    }
+
+    pub fn record_first_token(&self, time: Duration) {
+        self.time_to_first_token.observe(time.as_secs_f64());
+    }
+
+    pub fn record_cache_hit(&self, messages: usize, tokens: usize) {
+        self.cache_hits.inc();
+        self.cache_skipped_messages.inc_by(messages as u64);
+        self.cache_skipped_tokens.inc_by(tokens as u64);
+    }
+
+    pub fn record_cache_miss(&self) {
+        self.cache_misses.inc();
+    }
+}
+
+fn observe_speed(histogram: &Histogram, tokens: usize, time: Duration) {
+    // Too short to give a meaningful speed
+    if tokens == 0 || time.is_zero() {
+        return;
+    }
+
+    histogram.observe(tokens as f64 / time.as_secs_f64());
+}
+
+/// Count handled requests by route, only runs for requests that matched a route.
+#[handler]
+pub async fn track_requests(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+    ctrl: &mut FlowCtrl,
+) -> Result<(), Error> {
+    ctrl.call_next(req, depot, res).await;
+
+    let metrics = metrics_service(depot)?;
+    let service = agent_service(depot)?;
+
+    // Replace parameters in the path, to not create a label for every request ID
+    let base_path = &service.config().server.base_path;
+    let path = req.uri().path();
+    let path = path.strip_prefix(base_path.as_str()).unwrap_or(path);
+    let route = path
+        .split('/')
+        .map(|segment| {
+            match req
+                .params()
+                .iter()
+                .find(|(_, value)| value.as_str() == segment)
+            {
+                Some((name, _)) => format!("<{}>", name),
+                None => segment.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+
+    let status = res.status_code.map_or(200, |status| status.as_u16());
+    metrics
+        .requests
+        .with_label_values(&[&route, &status.to_string()])
+        .inc();
+
+    Ok(())
+}
+
+#[handler]
+async fn handle_metrics(depot: &mut Depot, res: &mut Response) -> Result<(), Error> {
+    let metrics = metrics_service(depot)?;
+    let service = agent_service(depot)?;
+    let queue = queue_service(depot)?;
+
+    // Gauges are sampled when scraped
+    let active_model = service.active_model().await;
+    let slots = active_model.as_ref().map_or(0, |model| model.num_slots());
+    let status = queue.status(slots);
+    metrics.queue_running.set(status.running.len() as i64);
+    metrics.queue_pending.set(status.pending.len() as i64);
+
+    let load_duration = active_model.map_or(0.0, |model| model.load_duration().as_secs_f64());
+    metrics.model_load_duration.set(load_duration);
+
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&metrics.registry.gather(), &mut buffer)?;
+    res.render(Text::Plain(String::from_utf8(buffer)?));
+
+    Ok(())
+}
@@ -0,0 +1,206 @@
+use std::{
+    collections::VecDeque,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use anyhow::{Context, Error};
+use salvo::Depot;
+use tokio_util::sync::CancellationToken;
+use tracing::{event, Level};
+
+use minmodmon_agent::{
+    config::QueueConfig,
+    types::{QueueStatus, QueuedRequest},
+    ActiveModelRef, AgentError, ModelSlot,
+};
+
+pub fn queue_service(depot: &Depot) -> Result<Arc<QueueService>, Error> {
+    depot
+        .obtain::<Arc<QueueService>>()
+        .ok()
+        .cloned()
+        .context("failed to get queue service")
+}
+
+/// Bounded FIFO of requests waiting for a free slot of the model.
+pub struct QueueService {
+    config: QueueConfig,
+    next_entry: AtomicU64,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    running: Vec<QueueEntry>,
+    pending: VecDeque<QueueEntry>,
+}
+
+struct QueueEntry {
+    key: u64,
+    id: String,
+    kind: &'static str,
+    since: Instant,
+}
+
+/// Returned when a request can't be admitted because the queue is full.
+#[derive(thiserror::Error, Debug)]
+#[error("server is busy, {pending} requests are already waiting")]
+pub struct QueueFull {
+    pub pending: usize,
+    pub retry_after: u64,
+}
+
+/// A slot of the model, marking the request as running until dropped.
+pub struct ModelPermit {
+    slot: ModelSlot,
+    _running: RunningEntry,
+}
+
+struct RunningEntry {
+    service: Arc<QueueService>,
+    key: u64,
+}
+
+/// Removes a pending entry when dropped, for example if the client disconnects while waiting.
+struct PendingEntry {
+    service: Arc<QueueService>,
+    key: u64,
+}
+
+impl QueueService {
+    pub fn create(config: QueueConfig) -> Result<Self, Error> {
+        let value = Self {
+            config,
+            next_entry: AtomicU64::new(0),
+            entries: Mutex::new(Entries::default()),
+        };
+
+        Ok(value)
+    }
+
+    /// Wait in line for a slot of the model, leaving the queue if `cancel` is cancelled.
+    pub async fn acquire(
+        self: &Arc<Self>,
+        id: &str,
+        kind: &'static str,
+        model: ActiveModelRef,
+        cancel: &CancellationToken,
+    ) -> Result<ModelPermit, Error> {
+        let key = self.next_entry.fetch_add(1, Ordering::SeqCst);
+        let since = Instant::now();
+
+        // Admit the request, if there's still room
+        {
+            let mut entries = self.entries.lock().unwrap();
+
+            let pending = entries.pending.len();
+            if pending >= self.config.max_pending {
+                event!(Level::WARN, id, pending, "rejecting request, queue is full");
+                let error = QueueFull {
+                    pending,
+                    retry_after: self.config.retry_after,
+                };
+                return Err(error.into());
+            }
+
+            entries.pending.push_back(QueueEntry {
+                key,
+                id: id.to_string(),
+                kind,
+                since,
+            });
+        }
+        let pending = PendingEntry {
+            service: self.clone(),
+            key,
+        };
+
+        // Slots are handed out in the order they were requested
+        let slot = tokio::select! {
+            slot = model.acquire_slot() => slot?,
+            _ = cancel.cancelled() => {
+                event!(Level::INFO, id, kind, "request cancelled while queued");
+                return Err(AgentError::Cancelled.into());
+            }
+        };
+        drop(pending);
+
+        let waited_ms = since.elapsed().as_millis() as u64;
+        event!(
+            Level::INFO,
+            id,
+            kind,
+            waited_ms,
+            "request admitted from queue"
+        );
+
+        {
+            let mut entries = self.entries.lock().unwrap();
+            entries.running.push(QueueEntry {
+                key,
+                id: id.to_string(),
+                kind,
+                since: Instant::now(),
+            });
+        }
+        let running = RunningEntry {
+            service: self.clone(),
+            key,
+        };
+
+        let value = ModelPermit {
+            slot,
+            _running: running,
+        };
+        Ok(value)
+    }
+
+    /// Get the current queue, for a model with `slots` slots to run requests in.
+    pub fn status(&self, slots: usize) -> QueueStatus {
+        let entries = self.entries.lock().unwrap();
+
+        QueueStatus {
+            max_pending: self.config.max_pending,
+            slots,
+            running: entries.running.iter().map(QueueEntry::to_info).collect(),
+            pending: entries.pending.iter().map(QueueEntry::to_info).collect(),
+        }
+    }
+}
+
+impl QueueEntry {
+    fn to_info(&self) -> QueuedRequest {
+        QueuedRequest {
+            id: Some(self.id.clone()),
+            kind: self.kind.to_string(),
+            elapsed_ms: self.since.elapsed().as_millis() as u64,
+        }
+    }
+}
+
+impl Deref for ModelPermit {
+    type Target = ModelSlot;
+
+    fn deref(&self) -> &ModelSlot {
+        &self.slot
+    }
+}
+
+impl Drop for PendingEntry {
+    fn drop(&mut self) {
+        let mut entries = self.service.entries.lock().unwrap();
+        entries.pending.retain(|entry| entry.key != self.key);
+    }
+}
+
+impl Drop for RunningEntry {
+    fn drop(&mut self) {
+        let mut entries = self.service.entries.lock().unwrap();
+        entries.running.retain(|entry| entry.key != self.key);
+    }
+}
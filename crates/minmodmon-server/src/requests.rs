@@ -0,0 +1,123 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::{Context, Error};
+use salvo::Depot;
+use tokio_util::sync::CancellationToken;
+
+pub fn request_service(depot: &Depot) -> Result<Arc<RequestService>, Error> {
+    depot
+        .obtain::<Arc<RequestService>>()
+        .ok()
+        .cloned()
+        .context("failed to get request service")
+}
+
+/// Tracks in-progress generation requests, so they can be cancelled.
+pub struct RequestService {
+    next_id: AtomicU64,
+    requests: Mutex<HashMap<String, RequestEntry>>,
+}
+
+struct RequestEntry {
+    token: CancellationToken,
+    /// Name of the API key that made the request, none if auth is disabled.
+    owner: Option<String>,
+}
+
+/// Registration of an in-progress request.
+///
+/// Dropping the handle cancels the request, so when a client disconnects and the handler gets
+/// dropped, generation stops too.
+pub struct RequestHandle {
+    service: Arc<RequestService>,
+    id: String,
+    token: CancellationToken,
+}
+
+impl RequestService {
+    pub fn create() -> Result<Self, Error> {
+        let value = Self {
+            next_id: AtomicU64::new(0),
+            requests: Mutex::new(HashMap::new()),
+        };
+
+        Ok(value)
+    }
+
+    pub fn register(self: &Arc<Self>, created: u64, owner: Option<String>) -> RequestHandle {
+        let index = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let id = format!("req-{}-{}", created, index);
+        let token = CancellationToken::new();
+
+        let entry = RequestEntry {
+            token: token.clone(),
+            owner,
+        };
+        let mut requests = self.requests.lock().unwrap();
+        requests.insert(id.clone(), entry);
+
+        RequestHandle {
+            service: self.clone(),
+            id,
+            token,
+        }
+    }
+
+    /// Cancel a request by ID, returns false if no such request is in progress.
+    ///
+    /// If an owner is given, only requests made by that owner can be cancelled.
+    pub fn cancel(&self, id: &str, owner: Option<&str>) -> bool {
+        let requests = self.requests.lock().unwrap();
+
+        let Some(entry) = requests.get(id) else {
+            return false;
+        };
+        if !is_owner(entry, owner) {
+            return false;
+        }
+        entry.token.cancel();
+
+        true
+    }
+
+    /// Check if a request in progress was made by the owner given.
+    pub fn is_owned_by(&self, id: &str, owner: &str) -> bool {
+        let requests = self.requests.lock().unwrap();
+
+        requests
+            .get(id)
+            .is_some_and(|entry| is_owner(entry, Some(owner)))
+    }
+}
+
+fn is_owner(entry: &RequestEntry, owner: Option<&str>) -> bool {
+    match owner {
+        Some(owner) => entry.owner.as_deref() == Some(owner),
+        None => true,
+    }
+}
+
+impl RequestHandle {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+}
+
+impl Drop for RequestHandle {
+    fn drop(&mut self) {
+        self.token.cancel();
+
+        let mut requests = self.service.requests.lock().unwrap();
+        requests.remove(&self.id);
+    }
+}
@@ -0,0 +1,63 @@
+use std::{
+    fs::Permissions,
+    os::unix::{fs::FileTypeExt, fs::PermissionsExt, net::UnixStream},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Error};
+use salvo::conn::{unix::UnixAcceptor, UnixListener};
+use salvo::Listener;
+use tracing::{event, Level};
+
+/// Listen on a unix domain socket, replacing a socket left behind by a previous run.
+///
+/// The returned guard removes the socket again when dropped.
+pub async fn bind(path: &Path, mode: Option<u32>) -> Result<(UnixAcceptor, SocketGuard), Error> {
+    remove_stale_socket(path)?;
+
+    let mut listener = UnixListener::new(path.to_path_buf());
+    if let Some(mode) = mode {
+        listener = listener.permissions(Permissions::from_mode(mode));
+    }
+    let acceptor = listener
+        .try_bind()
+        .await
+        .with_context(|| format!("failed to listen on {}", path.display()))?;
+
+    let guard = SocketGuard {
+        path: path.to_path_buf(),
+    };
+    Ok((acceptor, guard))
+}
+
+fn remove_stale_socket(path: &Path) -> Result<(), Error> {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+
+    // Never remove something that isn't ours to remove
+    if !metadata.file_type().is_socket() {
+        bail!("{} already exists and is not a socket", path.display());
+    }
+    if UnixStream::connect(path).is_ok() {
+        bail!("{} is in use by another process", path.display());
+    }
+
+    event!(Level::INFO, path = %path.display(), "removing stale socket");
+    std::fs::remove_file(path)
+        .with_context(|| format!("failed to remove stale socket {}", path.display()))?;
+
+    Ok(())
+}
+
+pub struct SocketGuard {
+    path: PathBuf,
+}
+
+impl Drop for SocketGuard {
+    fn drop(&mut self) {
+        if let Err(error) = std::fs::remove_file(&self.path) {
+            event!(Level::WARN, path = %self.path.display(), "failed to remove socket: {}", error);
+        }
+    }
+}